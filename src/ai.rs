@@ -0,0 +1,200 @@
+use std::{f32::consts::PI, time::Duration};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{ClientId, RenetServer};
+use rand::Rng;
+
+use crate::{
+    channels::ServerChannel,
+    game_match::Team,
+    health::{Dead, Health, SpawnPoints},
+    lag_compensation::{PositionHistory, ViewRewind},
+    messages::ServerMessage,
+    player::PlayerName,
+    player_controller::{ActionButtons, PlayerController},
+    rules::GameRules,
+    server::{InputQueue, PlayerClient},
+    weapon::{Weapon, Weapons},
+};
+
+/// Client IDs of bots count down from here. Real clients are very unlikely to
+/// be given one of these.
+const BOT_CLIENT_ID_MAX: u64 = u64::MAX;
+
+/// Bots notice players within this distance.
+const SIGHT_RANGE: f32 = 25.0;
+
+/// Bots stop chasing and start strafing around players within this distance.
+const ENGAGE_RANGE: f32 = 10.0;
+
+/// How long bots keep wandering in one direction or strafing to one side.
+const MIN_MANEUVER_TIME: f32 = 0.5;
+const MAX_MANEUVER_TIME: f32 = 2.5;
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct AiSettings {
+    /// Bots are added until there are this many players, and removed again as
+    /// humans join.
+    pub fill_to: usize,
+
+    /// Largest random error added to the angle bots aim at, in radians.
+    pub aim_error: f32,
+}
+
+impl Default for AiSettings {
+    fn default() -> Self {
+        Self {
+            fill_to: 0,
+            aim_error: 0.15,
+        }
+    }
+}
+
+/// A player controlled by the server. It has a `PlayerController` like human
+/// players, but sets it itself instead of receiving inputs.
+#[derive(Component)]
+pub struct AiBrain {
+    /// Direction the bot wanders in, or which way it circles its target.
+    heading: f32,
+    maneuver_timer: Timer,
+}
+
+impl Default for AiBrain {
+    fn default() -> Self {
+        Self {
+            heading: 0.0,
+            maneuver_timer: Timer::new(Duration::ZERO, TimerMode::Once),
+        }
+    }
+}
+
+/// Adds or removes one bot per call until the number of players matches
+/// `AiSettings::fill_to`.
+#[allow(clippy::too_many_arguments)]
+pub fn balance_bots(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    settings: Res<AiSettings>,
+    rules: Res<GameRules>,
+    weapons: Res<Weapons>,
+    spawn_points: Res<SpawnPoints>,
+    humans: Query<(), With<InputQueue>>,
+    bots: Query<(Entity, &PlayerClient), With<AiBrain>>,
+) {
+    let wanted = settings.fill_to.saturating_sub(humans.iter().count());
+    let current = bots.iter().count();
+
+    let message = if current < wanted {
+        // Reuse the lowest free ID so that bot IDs stay in a small range
+        let index = (0..)
+            .find(|index| {
+                bots.iter()
+                    .all(|(_, bot_client)| bot_client.raw() != BOT_CLIENT_ID_MAX - index)
+            })
+            .unwrap_or(0);
+        let client_id = ClientId::from_raw(BOT_CLIENT_ID_MAX - index);
+        let name = format!("Bot {}", index + 1);
+
+        commands.spawn((
+            PlayerClient(client_id),
+            PlayerName(name.clone()),
+            AiBrain::default(),
+            // Bots keep the weapon they spawn with
+            PlayerController {
+                weapon: rand::thread_rng().gen_range(0..weapons.len()) as u8,
+                ..default()
+            },
+            Weapon::new(&weapons),
+            Health::new(rules.max_health),
+            PositionHistory::default(),
+            ViewRewind::default(),
+            TransformBundle::from_transform(Transform::from_translation(
+                spawn_points.choose().extend(0.0),
+            )),
+        ));
+        ServerMessage::PlayerConnected { client_id, name }
+    } else if current > wanted {
+        let Some((entity, bot_client)) = bots.iter().next() else {
+            return;
+        };
+        commands.entity(entity).despawn();
+        ServerMessage::PlayerDisconnected {
+            client_id: **bot_client,
+        }
+    } else {
+        return;
+    };
+
+    match bincode::serialize(&message) {
+        Ok(bytes) => server.broadcast_message(ServerChannel::ServerMessages, bytes),
+        Err(err) => warn!("Failed to serialize bot message: {}", err),
+    }
+}
+
+type Bots<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut AiBrain,
+        &'static mut PlayerController,
+        &'static Transform,
+        Option<&'static Team>,
+    ),
+    Without<Dead>,
+>;
+
+type Targets<'w, 's> = Query<
+    'w,
+    's,
+    (Entity, &'static Transform, Option<&'static Team>),
+    (With<PlayerClient>, Without<Dead>),
+>;
+
+/// Decides what every bot does this tick: wander when nobody is in sight,
+/// chase the nearest opponent, and strafe around them while shooting once close.
+pub fn think(time: Res<Time>, settings: Res<AiSettings>, mut bots: Bots, targets: Targets) {
+    let mut rng = rand::thread_rng();
+
+    for (entity, mut brain, mut controller, transform, team) in bots.iter_mut() {
+        let position = transform.translation.xy();
+
+        let maneuver_finished = brain.maneuver_timer.tick(time.delta()).finished();
+        if maneuver_finished {
+            brain.heading = rng.gen_range(-PI..PI);
+            let duration = rng.gen_range(MIN_MANEUVER_TIME..MAX_MANEUVER_TIME);
+            brain.maneuver_timer = Timer::from_seconds(duration, TimerMode::Once);
+        }
+
+        let nearest = targets
+            .iter()
+            .filter(|(target, _, target_team)| {
+                *target != entity && (team.is_none() || *target_team != team)
+            })
+            .map(|(_, target_transform, _)| target_transform.translation.xy() - position)
+            .filter(|offset| offset.length() <= SIGHT_RANGE)
+            .min_by(|a, b| a.length_squared().total_cmp(&b.length_squared()));
+
+        let Some(offset) = nearest else {
+            // Wander
+            controller.move_direction = Vec2::from_angle(brain.heading);
+            controller.target_angle = brain.heading;
+            controller.buttons.set(ActionButtons::FIRE, false);
+            continue;
+        };
+
+        let direction = offset.normalize_or_zero();
+        controller.move_direction = if offset.length() > ENGAGE_RANGE {
+            // Chase
+            direction
+        } else {
+            // Strafe, switching sides whenever the heading changes
+            let side = if brain.heading >= 0.0 { 1.0 } else { -1.0 };
+            direction.perp() * side
+        };
+
+        let aim_error = rng.gen_range(-settings.aim_error..=settings.aim_error);
+        controller.target_angle = offset.y.atan2(offset.x) + aim_error;
+        controller.buttons.set(ActionButtons::FIRE, true);
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use rand::Rng;
+use renet::transport::{ConnectToken, NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES};
+
+/// Identifies the network protocol. It is derived from the crate version so
+/// that clients and servers of different versions refuse to connect to each
+/// other.
+pub const PROTOCOL_ID: u64 = fnv1a(env!("CARGO_PKG_VERSION").as_bytes());
+
+/// How long clients have to connect with a token after it was issued.
+pub const TOKEN_EXPIRE_SECONDS: u64 = 300;
+
+/// How long a connection may go without packets before it times out.
+const TIMEOUT_SECONDS: i32 = 15;
+
+pub type PrivateKey = [u8; NETCODE_KEY_BYTES];
+
+const fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+
+/// Writes a new random private key to `path`. The key is shared between the
+/// server and the token issuer, and must be kept secret from clients, so on
+/// Unix only the owner may read it. An existing key is only replaced if
+/// `overwrite` is set, since tokens issued with it stop working.
+pub fn generate_private_key(path: &Path, overwrite: bool) -> io::Result<()> {
+    let mut key: PrivateKey = [0; NETCODE_KEY_BYTES];
+    rand::thread_rng().fill(&mut key);
+
+    // The old file is removed rather than truncated, so that the new one is
+    // created with restricted permissions
+    if overwrite {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(&key)
+}
+
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    fs::read(path)?.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("private key must be {} bytes long", NETCODE_KEY_BYTES),
+        )
+    })
+}
+
+/// Client ID of a user. Every user has a single stable ID, so a second
+/// connection of the same user is refused while the first is active.
+pub fn client_id_for(username: &str) -> u64 {
+    fnv1a(username.as_bytes())
+}
+
+/// Client ID used when connecting without a token.
+pub fn random_client_id() -> u64 {
+    rand::random()
+}
+
+/// Issues a connect token that lets `username` join the servers at
+/// `server_addresses`.
+pub fn generate_token(
+    private_key: &PrivateKey,
+    username: &str,
+    server_addresses: Vec<SocketAddr>,
+) -> io::Result<ConnectToken> {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+
+    ConnectToken::generate(
+        current_time,
+        PROTOCOL_ID,
+        TOKEN_EXPIRE_SECONDS,
+        client_id_for(username),
+        TIMEOUT_SECONDS,
+        server_addresses,
+        Some(&username_to_user_data(username)),
+        private_key,
+    )
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))
+}
+
+pub fn write_token(path: &Path, token: &ConnectToken) -> io::Result<()> {
+    token.write(&mut BufWriter::new(fs::File::create(path)?))
+}
+
+pub fn read_token(path: &Path) -> io::Result<ConnectToken> {
+    ConnectToken::read(&mut BufReader::new(fs::File::open(path)?))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+/// Stores a username in the user data of a connect token or unsecure
+/// connection, prefixed with its length. Names that don't fit are truncated.
+pub fn username_to_user_data(username: &str) -> [u8; NETCODE_USER_DATA_BYTES] {
+    let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+    let mut len = username.len().min(NETCODE_USER_DATA_BYTES - 1);
+    while !username.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    user_data[0] = len as u8;
+    user_data[1..=len].copy_from_slice(&username.as_bytes()[..len]);
+    user_data
+}
+
+pub fn username_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<String> {
+    let len = user_data[0] as usize;
+    String::from_utf8(user_data[1..=len].to_vec()).ok()
+}
@@ -0,0 +1,300 @@
+use std::{
+    f32::consts::PI,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, SystemTime},
+};
+
+use bevy::{log::LogPlugin, prelude::*};
+use bevy_renet::renet::{
+    transport::{ClientAuthentication, NetcodeClientTransport},
+    ConnectionConfig, RenetClient,
+};
+use rand::Rng;
+
+use crate::{
+    auth::{generate_token, random_client_id, username_to_user_data, PrivateKey, PROTOCOL_ID},
+    channels::{ClientChannel, ServerChannel},
+    messages::{ClientMessage, Features, HandshakeResponse, ServerMessage, PROTOCOL_VERSION},
+    player_controller::{ActionButtons, PlayerInput},
+    snapshot::SnapshotDecoder,
+    tick::DEFAULT_TICK_RATE,
+};
+
+/// How often the bots' connection statistics are logged.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long bots keep doing the same thing before picking a new action.
+const MIN_ACTION_TIME: f32 = 0.5;
+const MAX_ACTION_TIME: f32 = 3.0;
+
+/// A headless client controlled by the computer. Every bot has its own
+/// connection, so that the server sees it like any other client.
+#[derive(Component)]
+struct Bot {
+    name: String,
+    client: RenetClient,
+    transport: NetcodeClientTransport,
+    decoder: SnapshotDecoder,
+    hello_sent: bool,
+    accepted: bool,
+    sequence: u32,
+
+    /// What the bot is currently doing, and for how much longer.
+    action: PlayerInput,
+    action_timer: Timer,
+
+    /// Snapshots received since the last report.
+    snapshots_received: u32,
+}
+
+impl Bot {
+    fn connect(
+        name: String,
+        server_address: SocketAddr,
+        private_key: Option<&PrivateKey>,
+        connection_config: ConnectionConfig,
+    ) -> std::io::Result<Self> {
+        let authentication = match private_key {
+            Some(private_key) => ClientAuthentication::Secure {
+                connect_token: generate_token(private_key, &name, vec![server_address])?,
+            },
+            None => ClientAuthentication::Unsecure {
+                protocol_id: PROTOCOL_ID,
+                client_id: random_client_id(),
+                server_addr: server_address,
+                user_data: Some(username_to_user_data(&name)),
+            },
+        };
+
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        let transport = NetcodeClientTransport::new(current_time, authentication, socket)
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+
+        Ok(Self {
+            name,
+            client: RenetClient::new(connection_config),
+            transport,
+            decoder: SnapshotDecoder::default(),
+            hello_sent: false,
+            accepted: false,
+            sequence: 0,
+            action: PlayerInput::default(),
+            action_timer: Timer::default(),
+            snapshots_received: 0,
+        })
+    }
+
+    fn send(&mut self, message: &ClientMessage) {
+        match bincode::serialize(message) {
+            Ok(bytes) => self.client.send_message(ClientChannel::Input, bytes),
+            Err(err) => warn!("{}: failed to serialize client message: {}", self.name, err),
+        }
+    }
+}
+
+/// Connects `count` bots to a server and keeps them moving, aiming and
+/// shooting at random, logging connection statistics as they play.
+pub fn run_bots(
+    server_address: SocketAddr,
+    count: usize,
+    private_key: Option<PrivateKey>,
+    connection_config: ConnectionConfig,
+) {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, LogPlugin::default()))
+        .insert_resource(Time::<Fixed>::from_hz(DEFAULT_TICK_RATE))
+        .add_systems(PreUpdate, update_transports)
+        .add_systems(Update, (receive_messages, choose_actions, report_stats))
+        .add_systems(FixedUpdate, send_inputs)
+        .add_systems(PostUpdate, send_packets);
+
+    // Secure client IDs are derived from the name, so every run gets its own
+    // names to let several runs join the same server
+    let run: u32 = rand::random();
+    for i in 0..count {
+        let name = format!("bot-{:08x}-{}", run, i);
+        match Bot::connect(
+            name.clone(),
+            server_address,
+            private_key.as_ref(),
+            connection_config.clone(),
+        ) {
+            Ok(bot) => {
+                app.world.spawn(bot);
+            }
+            Err(err) => error!("Failed to create {}: {}", name, err),
+        }
+    }
+
+    println!("Connecting {} bots to {}", count, server_address);
+    app.run();
+}
+
+fn update_transports(time: Res<Time>, mut bots: Query<&mut Bot>) {
+    for mut bot in bots.iter_mut() {
+        let bot = &mut *bot;
+        if bot.transport.disconnect_reason().is_some() {
+            continue;
+        }
+
+        bot.client.update(time.delta());
+        if let Err(err) = bot.transport.update(time.delta(), &mut bot.client) {
+            warn!("{}: {}", bot.name, err);
+        }
+    }
+}
+
+fn receive_messages(mut bots: Query<&mut Bot>, mut fixed_time: ResMut<Time<Fixed>>) {
+    for mut bot in bots.iter_mut() {
+        let bot = &mut *bot;
+
+        if bot.client.is_connected() && !bot.hello_sent {
+            bot.send(&ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+                features: Features::SUPPORTED,
+            });
+            bot.hello_sent = true;
+        }
+
+        for channel in [ServerChannel::ServerMessages, ServerChannel::PlayerData] {
+            while let Some(bytes) = bot.client.receive_message(channel) {
+                let msg: ServerMessage = match bincode::deserialize(&bytes) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        if bot.accepted {
+                            warn!(
+                                "{}: failed to deserialize server message: {}",
+                                bot.name, err
+                            );
+                        }
+                        continue;
+                    }
+                };
+
+                match msg {
+                    ServerMessage::Handshake(HandshakeResponse::Accepted { .. }) => {
+                        bot.accepted = true;
+                    }
+                    ServerMessage::Handshake(HandshakeResponse::Rejected(reason)) => {
+                        error!("{} was rejected: {}", bot.name, reason);
+                        bot.client.disconnect();
+                    }
+                    // All bots connect to the same server, so they share its
+                    // tick rate
+                    ServerMessage::ServerInfo { tick_rate, .. }
+                        if tick_rate.is_finite() && tick_rate > 0.0 =>
+                    {
+                        fixed_time.set_timestep_hz(tick_rate);
+                    }
+                    ServerMessage::Players(snapshot) => {
+                        // Snapshots against a lost baseline can't be used
+                        if bot.decoder.decode(snapshot).is_none() {
+                            continue;
+                        }
+                        bot.snapshots_received += 1;
+                    }
+                    // Bots don't keep track of the rest of the game
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// Gives every bot whose current action has run out a new random one.
+fn choose_actions(time: Res<Time>, mut bots: Query<&mut Bot>) {
+    let mut rng = rand::thread_rng();
+
+    for mut bot in bots.iter_mut() {
+        if !bot.action_timer.tick(time.delta()).finished() {
+            continue;
+        }
+
+        // Standing still now and then makes bots easier to hit
+        bot.action.move_direction = if rng.gen_bool(0.2) {
+            Vec2::ZERO
+        } else {
+            Vec2::from_angle(rng.gen_range(-PI..PI))
+        };
+        bot.action.target_angle = rng.gen_range(-PI..PI);
+        bot.action
+            .buttons
+            .set(ActionButtons::FIRE, rng.gen_bool(0.5));
+
+        let duration = rng.gen_range(MIN_ACTION_TIME..MAX_ACTION_TIME);
+        bot.action_timer = Timer::from_seconds(duration, TimerMode::Once);
+    }
+}
+
+fn send_inputs(mut bots: Query<&mut Bot>) {
+    for mut bot in bots.iter_mut() {
+        if !bot.accepted || !bot.client.is_connected() {
+            continue;
+        }
+
+        bot.sequence += 1;
+        let input = PlayerInput {
+            sequence: bot.sequence,
+            snapshot_tick: bot.decoder.latest_tick().unwrap_or_default(),
+            ..bot.action.clone()
+        };
+        bot.send(&ClientMessage::Input(input));
+    }
+}
+
+fn send_packets(mut bots: Query<&mut Bot>) {
+    for mut bot in bots.iter_mut() {
+        let bot = &mut *bot;
+        if bot.transport.disconnect_reason().is_some() {
+            continue;
+        }
+
+        if let Err(err) = bot.transport.send_packets(&mut bot.client) {
+            warn!("{}: {}", bot.name, err);
+        }
+    }
+}
+
+fn report_stats(time: Res<Time>, mut timer: Local<Option<Timer>>, mut bots: Query<&mut Bot>) {
+    let timer = timer.get_or_insert_with(|| Timer::new(REPORT_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let total = bots.iter().count();
+    let mut connected = 0;
+    let mut rtt_sum = 0.0;
+    let mut rtt_max: f64 = 0.0;
+    let mut packet_loss_sum = 0.0;
+    let mut snapshots = 0;
+
+    for mut bot in bots.iter_mut() {
+        snapshots += std::mem::take(&mut bot.snapshots_received);
+        if !bot.client.is_connected() {
+            continue;
+        }
+
+        let network_info = bot.client.network_info();
+        connected += 1;
+        rtt_sum += network_info.rtt;
+        rtt_max = rtt_max.max(network_info.rtt);
+        packet_loss_sum += network_info.packet_loss;
+    }
+
+    if connected == 0 {
+        info!("0/{} bots connected", total);
+        return;
+    }
+    info!(
+        "{}/{} bots connected, RTT {:.1} ms average ({:.1} ms max), {:.1}% packet loss, {:.1} snapshots/s per bot",
+        connected,
+        total,
+        rtt_sum / connected as f64,
+        rtt_max,
+        packet_loss_sum / connected as f64 * 100.0,
+        snapshots as f64 / REPORT_INTERVAL.as_secs_f64() / connected as f64,
+    );
+}
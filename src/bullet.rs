@@ -0,0 +1,163 @@
+use bevy::prelude::*;
+use rand::Rng;
+
+use crate::{
+    clock::epoch_millis,
+    health::{DamageEvent, Dead},
+    lag_compensation::{PositionHistory, ViewRewind},
+    map::Map,
+    player_controller::{PlayerController, PLAYER_RADIUS},
+    remote_state::{BulletPath, RemoteBulletState},
+    replication::Replicated,
+    tick::Tick,
+    weapon::{Weapon, Weapons},
+};
+
+pub const BULLET_RADIUS: f32 = 0.1;
+
+/// Server-side state of a bullet in flight.
+#[derive(Component)]
+pub struct Bullet {
+    /// Player that fired the bullet. Bullets never hit their own shooter.
+    pub owner: Entity,
+
+    /// Server time of the previous update, in milliseconds since epoch. The
+    /// path travelled since then is swept for hits so that fast bullets
+    /// cannot tunnel through players.
+    swept_until: u64,
+
+    /// How many ticks the shooter's view lagged behind the server when firing.
+    /// Targets are rewound by this much so hits match what the shooter saw.
+    rewind_ticks: u32,
+
+    damage: f32,
+}
+
+pub fn fire_bullets(
+    mut commands: Commands,
+    map: Res<Map>,
+    weapons: Res<Weapons>,
+    mut shooters: Query<
+        (
+            Entity,
+            &PlayerController,
+            &Transform,
+            &ViewRewind,
+            &mut Weapon,
+        ),
+        Without<Dead>,
+    >,
+) {
+    let now = epoch_millis();
+    let mut rng = rand::thread_rng();
+
+    for (entity, controller, transform, view_rewind, mut weapon) in shooters.iter_mut() {
+        let Some(definition) = weapon.fire(controller, &weapons, now) else {
+            continue;
+        };
+
+        // Spawn the bullets at the edge of the player so they do not start
+        // inside of them
+        let direction = Vec2::from_angle(controller.target_angle);
+        let origin = transform.translation.xy() + direction * PLAYER_RADIUS;
+        let half_spread = definition.spread.to_radians() / 2.0;
+
+        for _ in 0..definition.projectile_count {
+            let state = RemoteBulletState {
+                origin,
+                angle: controller.target_angle + rng.gen_range(-half_spread..=half_spread),
+                spawn_time: now,
+                speed: definition.projectile_speed,
+                wall_impact: definition.wall_impact,
+            };
+
+            commands.spawn((
+                state.path(&map),
+                // Bullets are only replicated to clients near them
+                Transform::from_translation(origin.extend(0.0)),
+                Bullet {
+                    owner: entity,
+                    swept_until: now,
+                    rewind_ticks: **view_rewind,
+                    damage: definition.damage,
+                },
+                Replicated,
+                state,
+            ));
+        }
+    }
+}
+
+type Targets<'w, 's> = Query<
+    'w,
+    's,
+    (Entity, &'static Transform, Option<&'static PositionHistory>),
+    (With<PlayerController>, Without<Dead>),
+>;
+
+pub fn update_bullets(
+    mut commands: Commands,
+    mut bullets: Query<
+        (Entity, &mut Bullet, &BulletPath, &mut Transform),
+        Without<PlayerController>,
+    >,
+    tick: Res<Tick>,
+    players: Targets,
+    mut damage_events: EventWriter<DamageEvent>,
+) {
+    let now = epoch_millis();
+
+    for (entity, mut bullet, path, mut transform) in bullets.iter_mut() {
+        // Bullets are removed once they expire or stop at a wall, after
+        // checking for hits on the way there
+        let (position, stopped) = match path.sample(now) {
+            Some((position, _)) => (position, false),
+            None => match path.end_position() {
+                Some(position) => (position, true),
+                None => {
+                    commands.entity(entity).despawn();
+                    continue;
+                }
+            },
+        };
+
+        let swept_from = bullet.swept_until;
+        bullet.swept_until = now;
+        transform.translation = position.extend(transform.translation.z);
+
+        // Test against where targets were in the shooter's view
+        let view_tick = Tick(tick.saturating_sub(bullet.rewind_ticks));
+        let target = players.iter().find(|(player, transform, history)| {
+            let target_position = history
+                .and_then(|history| history.position_at(view_tick))
+                .unwrap_or(transform.translation.xy());
+
+            *player != bullet.owner
+                && path.segments_between(swept_from, now).any(|(start, end)| {
+                    segment_distance(start, end, target_position) <= PLAYER_RADIUS + BULLET_RADIUS
+                })
+        });
+        if let Some((target, _, _)) = target {
+            damage_events.send(DamageEvent {
+                target,
+                attacker: bullet.owner,
+                amount: bullet.damage,
+            });
+        }
+        if target.is_some() || stopped {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Distance from `point` to the line segment between `start` and `end`.
+fn segment_distance(start: Vec2, end: Vec2, point: Vec2) -> f32 {
+    let segment = end - start;
+    let length_squared = segment.length_squared();
+    if length_squared == 0.0 {
+        return start.distance(point);
+    }
+
+    let t = ((point - start).dot(segment) / length_squared).clamp(0.0, 1.0);
+    (start + segment * t).distance(point)
+}
@@ -31,6 +31,10 @@ fn track_target(
         //     .translation
         //     .lerp(target_transform.translation, time.delta_seconds() * 5.0);
 
-        transform.translation = target_transform.translation;
+        // Keep the camera's depth so that the target stays in front of it
+        transform.translation = target_transform
+            .translation
+            .xy()
+            .extend(transform.translation.z);
     }
 }
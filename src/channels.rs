@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 
 // Channels
 
+#[derive(Debug, Clone, Copy)]
 pub enum ServerChannel {
     ServerMessages,
     PlayerData,
@@ -39,27 +40,36 @@ impl ServerChannel {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Component, Event)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Component, Event)]
 pub enum ClientChannel {
     Input,
+    Ping,
 }
 
 impl From<ClientChannel> for u8 {
     fn from(channel_id: ClientChannel) -> Self {
         match channel_id {
             ClientChannel::Input => 0,
+            ClientChannel::Ping => 1,
         }
     }
 }
 
 impl ClientChannel {
     pub fn channels_config() -> Vec<ChannelConfig> {
-        vec![ChannelConfig {
-            channel_id: Self::Input.into(),
-            max_memory_usage_bytes: 5 * 1024 * 1024,
-            send_type: SendType::ReliableOrdered {
-                resend_time: Duration::ZERO,
+        vec![
+            ChannelConfig {
+                channel_id: Self::Input.into(),
+                max_memory_usage_bytes: 5 * 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::ZERO,
+                },
             },
-        }]
+            ChannelConfig {
+                channel_id: Self::Ping.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::Unreliable,
+            },
+        ]
     }
 }
@@ -1,68 +1,222 @@
-use bevy::{prelude::*, utils::HashMap, window::close_on_esc};
+use bevy::{
+    ecs::system::SystemParam,
+    prelude::*,
+    utils::HashMap,
+    window::{close_on_esc, PresentMode, WindowMode},
+};
 use bevy_renet::{
-    renet::{transport::ClientAuthentication, ClientId, ConnectionConfig, RenetClient},
+    renet::{
+        transport::{ClientAuthentication, ConnectToken},
+        ClientId, ConnectionConfig, RenetClient,
+    },
     transport::NetcodeClientPlugin,
     RenetClientPlugin,
 };
-use renet::{transport::NetcodeClientTransport, DefaultChannel};
-use serde::{Deserialize, Serialize};
+use renet::transport::NetcodeClientTransport;
 use std::{
-    net::{SocketAddr, UdpSocket},
-    time::SystemTime,
+    fmt,
+    net::UdpSocket,
+    time::{Duration, SystemTime},
 };
 
 use crate::{
+    auth::{random_client_id, username_to_user_data, PROTOCOL_ID},
     camera_controller::{CameraController, CameraControllerPlugin},
-    messages::ServerMessage,
+    channels::{ClientChannel, ServerChannel},
+    clock::{epoch_millis, ServerClock},
+    config::ClientSettings,
+    game_match::{MatchStatus, Winner},
+    health::{Dead, Health},
+    map::Map,
+    messages::{Features, HandshakeResponse, PlayerStatus, ServerMessage, PROTOCOL_VERSION},
+    player::PlayerName,
 };
 use crate::{messages::ClientMessage, rendering::RendererPlugin};
 use crate::{
-    player_controller::{PlayerController, PlayerControllerPlugin},
-    remote_state::RemotePlayerControllerPlugin,
+    player_controller::{ControlSet, PlayerController, PlayerControllerPlugin, PlayerInput},
+    prediction::{LocalPlayer, LocalPlayerSnapshot, PredictionHistory, PredictionPlugin},
+    remote_state::{
+        InterpolationSettings, RemoteBulletState, RemotePlayerControllerPlugin, SnapshotBuffer,
+        SnapshotClock,
+    },
+    replication::{ClientReplication, ReplicationPlugin},
+    rules::GameRules,
+    snapshot::SnapshotDecoder,
+    tick::{TickRate, DEFAULT_TICK_RATE},
+};
+use crate::{
+    rendering::{BulletRendererBundleFactory, PlayerRendererBundleFactory},
+    GameState,
 };
-use crate::{rendering::PlayerRendererBundleFactory, GameState};
-
-#[derive(Debug, Default, Serialize, Deserialize, Component, Clone)]
-struct PlayerInput {
-    direction: Vec2,
-}
 
 #[derive(Debug, Default, Resource, Deref, DerefMut)]
 struct ClientMap(HashMap<ClientId, Entity>);
 
+// Last known status of every connected player, so that players entering the
+// area of interest are spawned in the right state.
+#[derive(Debug, Default, Resource, Deref, DerefMut)]
+struct PlayerStatuses(HashMap<ClientId, PlayerStatus>);
+
 #[derive(Debug, Resource, Deref, DerefMut)]
 struct LocalClientId(u64);
 
-pub fn run_client(server_address: SocketAddr, connection_config: ConnectionConfig) {
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
-    let client_id = current_time.as_millis() as u64;
-
-    let authentication = ClientAuthentication::Unsecure {
-        protocol_id: 0,
-        client_id,
-        server_addr: server_address,
-        user_data: None,
+// Progress of the handshake with the server. Until the server accepts this
+// client, only handshake messages are exchanged.
+#[derive(Debug, Default, Resource)]
+enum Handshake {
+    #[default]
+    NotSent,
+    Sent,
+    Accepted,
+    Rejected,
+}
+
+// Limits how many messages from the server may fail to decode within `window`
+// before the client gives up on the connection. Occasional failures are only
+// logged. Set from the client settings.
+#[derive(Debug, Resource, Clone, Copy)]
+struct MessageErrorSettings {
+    max_decode_errors: u32,
+    window: Duration,
+}
+
+// Decoding failures counted towards `MessageErrorSettings::max_decode_errors`.
+#[derive(Debug, Default, Resource)]
+struct DecodeErrors {
+    count: u32,
+    window_start: Duration,
+}
+
+// A message that could not be sent or received. Reported as an event so that
+// failures are logged and counted in one place.
+#[derive(Debug, Event)]
+enum MessageError {
+    Encode(bincode::Error),
+    Decode(bincode::Error),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Encode(err) => write!(f, "failed to serialize client message: {}", err),
+            Self::Decode(err) => write!(f, "failed to deserialize server message: {}", err),
+        }
+    }
+}
+
+// Everything that keeps this client's timeline in line with the server's.
+#[derive(SystemParam)]
+struct Clocks<'w> {
+    tick_rate: ResMut<'w, TickRate>,
+    fixed_time: ResMut<'w, Time<Fixed>>,
+    snapshot_clock: ResMut<'w, SnapshotClock>,
+    server_clock: ResMut<'w, ServerClock>,
+}
+
+// What the server told this client about the game it is playing.
+#[derive(SystemParam)]
+struct Game<'w> {
+    rules: ResMut<'w, GameRules>,
+    map: ResMut<'w, Map>,
+    match_status: ResMut<'w, MatchStatus>,
+    state: Res<'w, State<GameState>>,
+    next_state: ResMut<'w, NextState<GameState>>,
+}
+
+// Text at the top of the screen showing the phase of the match and the scores.
+#[derive(Component)]
+struct MatchHud;
+
+// Number of pixels per world unit the camera shows.
+#[derive(Debug, Resource, Deref)]
+struct CameraZoom(f32);
+
+// Sequence number of the last input sent to the server.
+#[derive(Debug, Default, Resource, Deref, DerefMut)]
+struct InputSequence(u32);
+
+pub fn run_client(
+    settings: ClientSettings,
+    connect_token: Option<ConnectToken>,
+    connection_config: ConnectionConfig,
+) {
+    // Secure servers only accept the client ID that the token was issued for
+    let (client_id, authentication) = match connect_token {
+        Some(connect_token) => (
+            connect_token.client_id,
+            ClientAuthentication::Secure { connect_token },
+        ),
+        None => {
+            let client_id = random_client_id();
+            (
+                client_id,
+                ClientAuthentication::Unsecure {
+                    protocol_id: PROTOCOL_ID,
+                    client_id,
+                    server_addr: settings.server_address,
+                    user_data: settings.name.as_deref().map(username_to_user_data),
+                },
+            )
+        }
     };
 
     let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
 
+    let window = Window {
+        title: "mp_arena_rs".to_string(),
+        resolution: (settings.window.width, settings.window.height).into(),
+        mode: if settings.window.fullscreen {
+            WindowMode::BorderlessFullscreen
+        } else {
+            WindowMode::Windowed
+        },
+        present_mode: if settings.window.vsync {
+            PresentMode::AutoVsync
+        } else {
+            PresentMode::AutoNoVsync
+        },
+        ..default()
+    };
+
     App::new()
         .add_plugins((
-            DefaultPlugins,
+            DefaultPlugins.set(WindowPlugin {
+                primary_window: Some(window),
+                ..default()
+            }),
             PlayerControllerPlugin { headless: false },
-            RemotePlayerControllerPlugin,
+            RemotePlayerControllerPlugin {
+                interpolation: InterpolationSettings {
+                    delay: Duration::from_millis(settings.interpolation_delay),
+                    ..default()
+                },
+            },
+            PredictionPlugin,
             CameraControllerPlugin,
             RenetClientPlugin,
             NetcodeClientPlugin,
             RendererPlugin,
+            ReplicationPlugin::Client,
         ))
         .add_state::<GameState>()
         .insert_resource(ClientMap::default())
-        .insert_resource(ClearColor(Color::hsl(0.0, 0.0, 0.05)))
+        .insert_resource(PlayerStatuses::default())
+        .insert_resource(MatchStatus::default())
+        .insert_resource(Time::<Fixed>::from_hz(DEFAULT_TICK_RATE))
+        // The map draws its own floor, so only the void around it is cleared
+        .insert_resource(ClearColor(Color::BLACK))
         .insert_resource(RenetClient::new(connection_config))
         .insert_resource(LocalClientId(client_id))
+        .insert_resource(CameraZoom(settings.zoom))
+        .insert_resource(InputSequence::default())
+        .insert_resource(Handshake::default())
+        .insert_resource(MessageErrorSettings {
+            max_decode_errors: settings.max_decode_errors,
+            window: Duration::from_millis(settings.decode_error_window),
+        })
+        .insert_resource(DecodeErrors::default())
+        .add_event::<MessageError>()
+        .insert_resource(SnapshotDecoder::default())
         .insert_resource(
             NetcodeClientTransport::new(
                 SystemTime::now()
@@ -73,84 +227,602 @@ pub fn run_client(server_address: SocketAddr, connection_config: ConnectionConfi
             )
             .unwrap(),
         )
-        .add_systems(Startup, (spawn_camera, spawn_players, spawn_local_player))
-        .add_systems(Update, (client_send_input, client_receive))
+        .add_systems(
+            Startup,
+            (
+                (spawn_local_player, apply_deferred, spawn_camera).chain(),
+                spawn_match_hud,
+            ),
+        )
+        .add_systems(
+            FixedUpdate,
+            client_send_input
+                .after(ControlSet::Read)
+                .before(ControlSet::Apply),
+        )
+        .add_systems(
+            Update,
+            (
+                client_send_hello,
+                client_send_ping,
+                client_receive,
+                handle_message_errors,
+                spawn_bullets,
+                update_match_hud,
+            )
+                .chain(),
+        )
         .add_systems(Update, close_on_esc)
         .run();
 }
 
-fn client_send_input(mut client: ResMut<RenetClient>, controllers: Query<&PlayerController>) {
-    let message =
-        bincode::serialize(&ClientMessage::Controller(controllers.single().clone())).unwrap();
-    client.send_message(DefaultChannel::ReliableOrdered, message);
+// Introduces this client to the server once connected.
+fn client_send_hello(
+    mut client: ResMut<RenetClient>,
+    mut handshake: ResMut<Handshake>,
+    mut errors: EventWriter<MessageError>,
+) {
+    if !matches!(*handshake, Handshake::NotSent) || !client.is_connected() {
+        return;
+    }
+
+    match bincode::serialize(&ClientMessage::Hello {
+        version: PROTOCOL_VERSION,
+        features: Features::SUPPORTED,
+    }) {
+        Ok(message) => client.send_message(ClientChannel::Input, message),
+        Err(err) => errors.send(MessageError::Encode(err)),
+    }
+    *handshake = Handshake::Sent;
+}
+
+// Pings the server regularly to keep the server clock estimate up to date.
+fn client_send_ping(
+    time: Res<Time>,
+    mut client: ResMut<RenetClient>,
+    server_clock: Res<ServerClock>,
+    handshake: Res<Handshake>,
+    mut errors: EventWriter<MessageError>,
+    mut last_ping: Local<Option<Duration>>,
+) {
+    if !client.is_connected() || !matches!(*handshake, Handshake::Accepted) {
+        return;
+    }
+    if last_ping.is_some_and(|last_ping| time.elapsed() - last_ping < server_clock.ping_interval())
+    {
+        return;
+    }
+    *last_ping = Some(time.elapsed());
+
+    match bincode::serialize(&ClientMessage::Ping {
+        client_time: epoch_millis(),
+    }) {
+        Ok(message) => client.send_message(ClientChannel::Ping, message),
+        Err(err) => errors.send(MessageError::Encode(err)),
+    }
+}
+
+// Sends the local player's input for this tick and remembers it so that it can
+// be replayed when the server's state for the local player arrives.
+fn client_send_input(
+    mut client: ResMut<RenetClient>,
+    mut sequence: ResMut<InputSequence>,
+    mut controllers: Query<(&PlayerController, &mut PredictionHistory), With<LocalPlayer>>,
+    snapshot_clock: Res<SnapshotClock>,
+    interpolation: Res<InterpolationSettings>,
+    handshake: Res<Handshake>,
+    mut errors: EventWriter<MessageError>,
+) {
+    // There is normally exactly one local player, but there is nothing to send
+    // before it is spawned
+    let mut local_players = controllers.iter_mut();
+    let Some((controller, mut history)) = local_players.next() else {
+        return;
+    };
+    if local_players.next().is_some() {
+        warn!("Found several local players, only sending input for one of them");
+    }
+
+    **sequence += 1;
+
+    // Tell the server what this client currently sees, so that hits can be
+    // judged against it
+    let input = PlayerInput {
+        snapshot_tick: snapshot_clock.latest_tick(),
+        interpolation_delay_ms: interpolation.delay.as_millis().min(u16::MAX as u128) as u16,
+        ..controller.to_input(**sequence)
+    };
+
+    if client.is_connected() && matches!(*handshake, Handshake::Accepted) {
+        match bincode::serialize(&ClientMessage::Input(input.clone())) {
+            Ok(message) => client.send_message(ClientChannel::Input, message),
+            Err(err) => errors.send(MessageError::Encode(err)),
+        }
+    }
+
+    history.push(input);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn client_receive(
     mut commands: Commands,
     mut player_factory: PlayerRendererBundleFactory,
     mut client: ResMut<RenetClient>,
     mut client_map: ResMut<ClientMap>,
+    mut player_statuses: ResMut<PlayerStatuses>,
     local_client_id: Res<LocalClientId>,
+    mut local_snapshots: EventWriter<LocalPlayerSnapshot>,
+    mut clocks: Clocks,
+    mut game: Game,
+    mut snapshot_buffers: Query<&mut SnapshotBuffer>,
+    mut replication: ResMut<ClientReplication>,
+    mut snapshot_decoder: ResMut<SnapshotDecoder>,
+    mut handshake: ResMut<Handshake>,
+    mut errors: EventWriter<MessageError>,
 ) {
-    while let Some(msg) = client.receive_message(DefaultChannel::ReliableOrdered) {
-        let msg: ServerMessage = bincode::deserialize(&msg).unwrap();
+    // Lifecycle events arrive reliably on ServerMessages, while player snapshots
+    // are sent unreliably on PlayerData.
+    for channel in [ServerChannel::ServerMessages, ServerChannel::PlayerData] {
+        while let Some(msg) = client.receive_message(channel) {
+            let accepted = matches!(*handshake, Handshake::Accepted);
+            let msg: ServerMessage = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    // Messages sent before the handshake may come from a server
+                    // running another version
+                    if accepted {
+                        errors.send(MessageError::Decode(err));
+                    }
+                    continue;
+                }
+            };
 
-        match msg {
-            ServerMessage::PlayerConnected { client_id } => {
-                info!("Player {} connected.", client_id);
-            }
-            ServerMessage::PlayerDisconnected { client_id } => {
-                info!("Player {} disconnected.", client_id);
-            }
-            ServerMessage::Players(players) => {
-                for (client_id, controller) in players {
-                    // if client_id.raw() == **local_client_id {
-                    //     continue;
-                    // }
+            match msg {
+                ServerMessage::Handshake(HandshakeResponse::Accepted { features }) => {
+                    info!("Joined the server with features {:?}.", features);
+                    *handshake = Handshake::Accepted;
+                }
+                ServerMessage::Handshake(HandshakeResponse::Rejected(reason)) => {
+                    error!("The server rejected this client: {}", reason);
+                    show_message(&mut commands, format!("Rejected by the server: {}", reason));
+                    client.disconnect();
+                    *handshake = Handshake::Rejected;
+                    return;
+                }
+                // Everything else describes a game this client hasn't joined yet
+                _ if !accepted => {}
+                ServerMessage::ServerInfo {
+                    tick_rate: server_tick_rate,
+                    rules: server_rules,
+                } => {
+                    *game.rules = server_rules;
+                    if !(server_tick_rate.is_finite() && server_tick_rate > 0.0) {
+                        warn!("Ignoring invalid tick rate {}", server_tick_rate);
+                        continue;
+                    }
 
-                    if let Some(player_entity) = client_map.get_mut(&client_id) {
-                        commands.entity(*player_entity).insert(controller);
-                    } else {
-                        // Spawn player
-                        let player_entity = commands
-                            .spawn((controller, TransformBundle::default()))
-                            .id();
+                    // Inputs are sent and predicted on every tick, so the
+                    // fixed timestep has to match the server's
+                    *clocks.tick_rate = TickRate(server_tick_rate);
+                    clocks.fixed_time.set_timestep_hz(server_tick_rate);
+                }
+                ServerMessage::Map(server_map) => {
+                    info!("Playing on {}.", server_map.name);
+                    *game.map = server_map;
+                }
+                ServerMessage::MatchStatus(status) => {
+                    if status.state != *game.state.get() {
+                        match status.state {
+                            GameState::InProgress => info!("The match has started."),
+                            GameState::PostMatch => info!("The match is over."),
+                            _ => {}
+                        }
+                        game.next_state.set(status.state);
+                    }
 
-                        // Spawn player renderer
-                        commands.spawn(player_factory.build(player_entity));
+                    // Teams are only known from the scores, and players keep
+                    // them until the next status
+                    for score in status.scores.iter() {
+                        let (Some(team), Some(player_entity)) =
+                            (score.team, client_map.get(&score.client_id))
+                        else {
+                            continue;
+                        };
+                        commands.entity(*player_entity).insert(team);
+                    }
+                    *game.match_status = status;
+                }
+                ServerMessage::Pong {
+                    client_time,
+                    receive_time,
+                    server_time,
+                } => {
+                    let was_synchronized = clocks.server_clock.is_synchronized();
+                    clocks
+                        .server_clock
+                        .observe_pong(client_time, receive_time, server_time);
 
-                        // Register client ID -> player mapping
-                        client_map.insert(client_id, player_entity);
+                    if !was_synchronized && clocks.server_clock.is_synchronized() {
+                        info!(
+                            "Synchronized clock with the server (round trip time {:?}).",
+                            clocks.server_clock.rtt()
+                        );
                     }
                 }
+                ServerMessage::PlayerConnected { client_id, name } => {
+                    info!("Player {} ({}) connected.", client_id, name);
+
+                    // The local player exists before the server names it
+                    if let Some(player_entity) = client_map.get(&client_id) {
+                        commands
+                            .entity(*player_entity)
+                            .insert(PlayerName(name.clone()));
+                    }
+                    player_statuses.insert(
+                        client_id,
+                        PlayerStatus {
+                            name,
+                            health: game.rules.max_health,
+                            dead: false,
+                        },
+                    );
+                }
+                ServerMessage::PlayerDisconnected { client_id } => {
+                    info!("Player {} disconnected.", client_id);
+                    player_statuses.remove(&client_id);
+
+                    if client_id.raw() == **local_client_id {
+                        continue;
+                    }
+                    if let Some(player_entity) = client_map.remove(&client_id) {
+                        commands.entity(player_entity).despawn();
+                    }
+                }
+                ServerMessage::ConnectedPlayers(players) => {
+                    player_statuses.extend(players);
+                }
+                ServerMessage::PlayersInRange(players) => {
+                    for (client_id, status) in players {
+                        if let Some(player_entity) = client_map.get(&client_id) {
+                            let mut player = commands.entity(*player_entity);
+                            player.insert(Health {
+                                current: status.health,
+                                max: game.rules.max_health,
+                            });
+                            if status.dead {
+                                player.insert(Dead);
+                            } else {
+                                player.remove::<Dead>();
+                            }
+                        }
+                        player_statuses.insert(client_id, status);
+                    }
+                }
+                ServerMessage::PlayerDamaged { client_id, health } => {
+                    if let Some(status) = player_statuses.get_mut(&client_id) {
+                        status.health = health;
+                    }
+                    if let Some(player_entity) = client_map.get(&client_id) {
+                        commands.entity(*player_entity).insert(Health {
+                            current: health,
+                            max: game.rules.max_health,
+                        });
+                    }
+                }
+                ServerMessage::PlayerDied { client_id, killer } => {
+                    match killer {
+                        Some(killer) => info!("Player {} was killed by {}.", client_id, killer),
+                        None => info!("Player {} died.", client_id),
+                    }
+
+                    if let Some(status) = player_statuses.get_mut(&client_id) {
+                        status.dead = true;
+                    }
+
+                    if let Some(player_entity) = client_map.get(&client_id) {
+                        commands.entity(*player_entity).insert(Dead);
+                    }
+                }
+                ServerMessage::PlayerRespawned { client_id, .. } => {
+                    if let Some(status) = player_statuses.get_mut(&client_id) {
+                        status.health = game.rules.max_health;
+                        status.dead = false;
+                    }
+
+                    let Some(player_entity) = client_map.get(&client_id) else {
+                        continue;
+                    };
+                    commands
+                        .entity(*player_entity)
+                        .remove::<Dead>()
+                        .insert(Health::new(game.rules.max_health));
+
+                    // Don't interpolate from where the player died
+                    if let Ok(mut buffer) = snapshot_buffers.get_mut(*player_entity) {
+                        buffer.clear();
+                    }
+                }
+                ServerMessage::Players(snapshot) => {
+                    let tick = snapshot.tick;
+                    let input_ack = snapshot.input_ack;
+                    let Some(decoded) = snapshot_decoder.decode(snapshot) else {
+                        continue;
+                    };
+                    let tick_rate = *clocks.tick_rate;
+                    clocks.snapshot_clock.observe(tick, tick_rate);
+
+                    // Players that left this client's area of interest are
+                    // spawned again if they come back
+                    for client_id in decoded.removed {
+                        if client_id.raw() == **local_client_id {
+                            continue;
+                        }
+                        if let Some(player_entity) = client_map.remove(&client_id) {
+                            commands.entity(player_entity).despawn();
+                        }
+                    }
+
+                    for (client_id, state) in decoded.players {
+                        let state = state.dequantize();
+
+                        // The local player is predicted, so its state is used
+                        // for reconciliation instead
+                        if client_id.raw() == **local_client_id {
+                            local_snapshots.send(LocalPlayerSnapshot {
+                                tick,
+                                state,
+                                input_ack,
+                            });
+                            continue;
+                        }
+
+                        if let Some(player_entity) = client_map.get(&client_id) {
+                            // A player spawned earlier this frame is not
+                            // queryable yet, in which case the snapshot is
+                            // dropped like a lost packet
+                            if let Ok(mut buffer) = snapshot_buffers.get_mut(*player_entity) {
+                                buffer.insert(tick, state);
+                            }
+                        } else {
+                            // Spawn player
+                            let mut buffer = SnapshotBuffer::default();
+                            buffer.insert(tick, state);
+                            let mut player = commands.spawn((buffer, TransformBundle::default()));
+                            if let Some(status) = player_statuses.get(&client_id) {
+                                player.insert((
+                                    PlayerName(status.name.clone()),
+                                    Health {
+                                        current: status.health,
+                                        max: game.rules.max_health,
+                                    },
+                                ));
+                                if status.dead {
+                                    player.insert(Dead);
+                                }
+                            }
+                            if let Some(team) = game.match_status.team(client_id) {
+                                player.insert(team);
+                            }
+                            let player_entity = player.id();
+
+                            // Spawn player renderer
+                            commands.spawn(player_factory.build(player_entity));
+
+                            // Register client ID -> player mapping
+                            client_map.insert(client_id, player_entity);
+                        }
+                    }
+                }
+                ServerMessage::Replication(messages) => {
+                    replication.receive(messages);
+                }
             }
-            ServerMessage::Bullets(bullets) => {}
         }
     }
 }
 
-fn spawn_camera(mut commands: Commands) {
-    let mut camera_bundle = Camera2dBundle::default();
-    camera_bundle.projection.scale = 0.05;
+// Places replicated bullets in the world and gives them a renderer.
+fn spawn_bullets(
+    mut commands: Commands,
+    mut bullet_factory: BulletRendererBundleFactory,
+    server_clock: Res<ServerClock>,
+    map: Res<Map>,
+    bullets: Query<(Entity, &RemoteBulletState), Added<RemoteBulletState>>,
+) {
+    let now = server_clock.now();
+
+    for (bullet_entity, state) in bullets.iter() {
+        // Bullets are traced through the map here as well, so that they stop
+        // at walls without waiting for the server
+        let path = state.path(&map);
+        let Some((position, direction)) = path.sample(now) else {
+            commands.entity(bullet_entity).despawn();
+            continue;
+        };
+        let transform = Transform {
+            translation: position.extend(0.0),
+            rotation: Quat::from_rotation_z(direction.y.atan2(direction.x)),
+            ..default()
+        };
 
-    commands.spawn((camera_bundle, CameraController::default()));
+        commands
+            .entity(bullet_entity)
+            .insert((path, TransformBundle::from_transform(transform)));
+        commands.spawn(bullet_factory.build(bullet_entity, transform));
+    }
 }
 
-fn spawn_local_player(mut commands: Commands, mut player_factory: PlayerRendererBundleFactory) {
-    commands.spawn((PlayerController::default(), TransformBundle::default()));
+// Logs message errors, and disconnects if the server sends too many messages
+// that can't be decoded.
+fn handle_message_errors(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<MessageErrorSettings>,
+    mut decode_errors: ResMut<DecodeErrors>,
+    mut errors: EventReader<MessageError>,
+    mut client: ResMut<RenetClient>,
+) {
+    if time.elapsed() - decode_errors.window_start >= settings.window {
+        decode_errors.count = 0;
+        decode_errors.window_start = time.elapsed();
+    }
+
+    for error in errors.read() {
+        warn!("{}", error);
+
+        if !matches!(error, MessageError::Decode(_)) || client.is_disconnected() {
+            continue;
+        }
+        decode_errors.count += 1;
+
+        if decode_errors.count > settings.max_decode_errors {
+            error!(
+                "Received {} malformed messages within {:?}, disconnecting",
+                decode_errors.count, settings.window
+            );
+            show_message(
+                &mut commands,
+                "Disconnected: the server sent too many malformed messages".to_string(),
+            );
+            client.disconnect();
+        }
+    }
 }
 
-fn spawn_players(
+// Shows a message in the middle of the screen, such as why this client was
+// disconnected.
+fn show_message(commands: &mut Commands, message: String) {
+    commands.spawn(
+        TextBundle::from_section(
+            message,
+            TextStyle {
+                font_size: 24.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            margin: UiRect::all(Val::Auto),
+            ..default()
+        }),
+    );
+}
+
+fn spawn_match_hud(mut commands: Commands) {
+    commands.spawn((
+        MatchHud,
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 20.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+    ));
+}
+
+// Shows the phase of the match and, once it started, the scores.
+fn update_match_hud(
+    status: Res<MatchStatus>,
+    rules: Res<GameRules>,
+    player_statuses: Res<PlayerStatuses>,
+    server_clock: Res<ServerClock>,
+    mut huds: Query<&mut Text, With<MatchHud>>,
+) {
+    let name = |client_id: ClientId| {
+        player_statuses
+            .get(&client_id)
+            .map_or_else(|| client_id.to_string(), |status| status.name.clone())
+    };
+    let seconds_left = status
+        .ends_at
+        .map(|ends_at| ends_at.saturating_sub(server_clock.now()).div_ceil(1000));
+
+    let mut lines = vec![match status.state {
+        GameState::Loading => "Joining...".to_string(),
+        GameState::Warmup => format!("Warmup: waiting for {} players", rules.min_players),
+        GameState::Countdown => format!("Match starts in {}", seconds_left.unwrap_or(0)),
+        GameState::InProgress => match seconds_left {
+            Some(seconds) => format!("{}: {}:{:02}", rules.mode, seconds / 60, seconds % 60),
+            None => rules.mode.to_string(),
+        },
+        GameState::PostMatch => match status.winner {
+            Some(Winner::Player(client_id)) => format!("{} wins!", name(client_id)),
+            Some(Winner::Team(team)) => format!("{} team wins!", team),
+            _ => "Draw!".to_string(),
+        },
+    }];
+
+    // Scores are only kept while a match is played
+    if matches!(status.state, GameState::InProgress | GameState::PostMatch) {
+        if let [(Winner::Team(first), first_kills), (Winner::Team(second), second_kills)] =
+            status.standings(rules.mode).as_slice()
+        {
+            lines.push(format!(
+                "{} {} - {} {}",
+                first, first_kills, second_kills, second
+            ));
+        }
+        for score in status.scores.iter() {
+            let team = score.team.map(|team| format!("[{}] ", team));
+            lines.push(format!(
+                "{}{}: {} kills, {} deaths",
+                team.unwrap_or_default(),
+                name(score.client_id),
+                score.kills,
+                score.deaths
+            ));
+        }
+    }
+
+    let text = lines.join("\n");
+    for mut hud in huds.iter_mut() {
+        if hud.sections[0].value != text {
+            hud.sections[0].value = text.clone();
+        }
+    }
+}
+
+fn spawn_camera(
+    mut commands: Commands,
+    zoom: Res<CameraZoom>,
+    local_players: Query<Entity, With<LocalPlayer>>,
+) {
+    let mut camera_bundle = Camera2dBundle::default();
+    camera_bundle.projection.scale = 1.0 / **zoom;
+
+    commands.spawn((
+        camera_bundle,
+        CameraController {
+            target: local_players.get_single().ok(),
+        },
+    ));
+}
+
+fn spawn_local_player(
     mut commands: Commands,
     mut player_factory: PlayerRendererBundleFactory,
-    mut cameras: Query<&mut CameraController>,
+    mut client_map: ResMut<ClientMap>,
+    local_client_id: Res<LocalClientId>,
+    rules: Res<GameRules>,
 ) {
-    // let entity = commands
-    //     .spawn(player_factory.build())
-    //     .insert(PlayerController::default())
-    //     .id();
-
-    // for mut camera_controller in cameras.iter_mut() {
-    //     camera_controller.target = Some(entity);
-    // }
+    let player_entity = commands
+        .spawn((
+            LocalPlayer,
+            PlayerController::default(),
+            PredictionHistory::default(),
+            Health::new(rules.max_health),
+            TransformBundle::default(),
+        ))
+        .id();
+    client_map.insert(ClientId::from_raw(**local_client_id), player_entity);
+
+    commands.spawn(player_factory.build(player_entity));
 }
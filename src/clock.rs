@@ -0,0 +1,99 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+
+/// Number of recent pings the clock estimate is based on.
+const CLOCK_SAMPLES: usize = 8;
+
+/// Time between pings once the clock is synchronized.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time between pings while the clock is still being synchronized.
+const FAST_PING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Milliseconds since epoch on this machine. The server's value is the time
+/// base of replicated state such as bullets.
+pub fn epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ClockSample {
+    rtt_ms: u64,
+    offset_ms: i64,
+}
+
+/// Client estimate of the server's clock, from an NTP-style exchange of pings
+/// and pongs. Until the first pong arrives, the server's clock is assumed to
+/// match this machine's.
+#[derive(Resource, Default, Debug)]
+pub struct ServerClock {
+    samples: VecDeque<ClockSample>,
+
+    /// Milliseconds to add to this machine's time to get the server's.
+    offset_ms: i64,
+
+    rtt: Duration,
+}
+
+impl ServerClock {
+    /// Updates the estimate from a pong. `client_time` is when the ping was
+    /// sent according to this machine, and `receive_time` and `server_time`
+    /// when the server received it and answered it according to the server.
+    pub fn observe_pong(&mut self, client_time: u64, receive_time: u64, server_time: u64) {
+        let now = epoch_millis();
+
+        // Time the server held on to the ping isn't part of the round trip
+        let server_delay_ms = server_time.saturating_sub(receive_time);
+        let rtt_ms = now
+            .saturating_sub(client_time)
+            .saturating_sub(server_delay_ms);
+
+        // Average the offsets seen by the ping and by the pong, so that their
+        // delays cancel out as far as they are equal
+        let offset_ms =
+            ((receive_time as i64 - client_time as i64) + (server_time as i64 - now as i64)) / 2;
+
+        if self.samples.len() >= CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockSample { rtt_ms, offset_ms });
+
+        // The exchange with the shortest round trip was delayed the least, so
+        // its offset is the most accurate
+        if let Some(best) = self.samples.iter().min_by_key(|sample| sample.rtt_ms) {
+            self.offset_ms = best.offset_ms;
+        }
+        let total_rtt_ms: u64 = self.samples.iter().map(|sample| sample.rtt_ms).sum();
+        self.rtt = Duration::from_millis(total_rtt_ms / self.samples.len() as u64);
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.samples.len() >= CLOCK_SAMPLES
+    }
+
+    /// Average round trip time of recent pings.
+    pub fn rtt(&self) -> Duration {
+        self.rtt
+    }
+
+    /// How long to wait before sending the next ping.
+    pub fn ping_interval(&self) -> Duration {
+        if self.is_synchronized() {
+            PING_INTERVAL
+        } else {
+            FAST_PING_INTERVAL
+        }
+    }
+
+    /// Estimated current time on the server, in milliseconds since epoch.
+    pub fn now(&self) -> u64 {
+        (epoch_millis() as i64 + self.offset_ms).max(0) as u64
+    }
+}
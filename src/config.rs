@@ -0,0 +1,512 @@
+use std::{
+    fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use renet::transport::NETCODE_USER_DATA_BYTES;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    interest::InterestSettings,
+    rules::{GameMode, GameRules},
+    tick::DEFAULT_TICK_RATE,
+};
+
+const DEFAULT_PORT: u16 = 20987;
+
+/// Longest rewind allowed for lag compensation, in milliseconds. Every player
+/// keeps a position for each tick of it.
+const MAX_REWIND_LIMIT: u64 = 1000;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Read(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            Self::Parse(path, err) => write!(f, "invalid config in {}: {}", path.display(), err),
+            Self::Invalid(problem) => write!(f, "invalid config: {}", problem),
+        }
+    }
+}
+
+/// Everything a server can be configured with. Settings are read from a TOML
+/// file, and can be overridden from the command line with `ServerArgs`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Address the server listens on.
+    pub bind_address: SocketAddr,
+
+    /// Addresses clients connect to, which connect tokens must be issued for.
+    /// Defaults to localhost on the bound port.
+    pub public_addresses: Vec<SocketAddr>,
+
+    pub max_clients: usize,
+
+    /// Private key to accept connect tokens with. Without it, any client can
+    /// connect.
+    pub key_file: Option<PathBuf>,
+
+    /// RON file of the map to play on. Defaults to a built-in arena.
+    pub map: Option<PathBuf>,
+
+    /// RON file of the weapons players can choose from. Defaults to
+    /// weapons/default.ron.
+    pub weapons: Option<PathBuf>,
+
+    /// Number of players to fill the server up to with bots.
+    pub bots: usize,
+
+    /// Number of times the game is simulated per second.
+    pub tick_rate: f64,
+
+    /// Number of snapshots sent to each client per second. Rounded to a whole
+    /// number of ticks between snapshots.
+    pub send_rate: f64,
+
+    /// Furthest back in time hits are judged against, in milliseconds.
+    /// Clients with more latency than this have to lead their shots.
+    pub max_rewind: u64,
+
+    /// Which players each client is sent.
+    pub interest: InterestSettings,
+
+    pub rules: GameRules,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)),
+            public_addresses: Vec::new(),
+            max_clients: 64,
+            key_file: None,
+            map: None,
+            weapons: None,
+            bots: 0,
+            tick_rate: DEFAULT_TICK_RATE,
+            send_rate: DEFAULT_TICK_RATE,
+            max_rewind: 250,
+            interest: InterestSettings::default(),
+            rules: GameRules::default(),
+        }
+    }
+}
+
+/// Reads settings from a TOML file.
+fn load<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|err| ConfigError::Read(path.to_owned(), err))?;
+    toml::from_str(&text).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+}
+
+impl ServerSettings {
+    /// Addresses to issue connect tokens for, falling back to localhost on the
+    /// bound port.
+    pub fn public_addresses(&self) -> Vec<SocketAddr> {
+        if self.public_addresses.is_empty() {
+            vec![SocketAddr::from((
+                Ipv4Addr::LOCALHOST,
+                self.bind_address.port(),
+            ))]
+        } else {
+            self.public_addresses.clone()
+        }
+    }
+
+    /// Checks the settings for values the server can't run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |problem: String| Err(ConfigError::Invalid(problem));
+
+        if self.max_clients == 0 {
+            return invalid("max_clients must be at least 1".to_string());
+        }
+        if !(self.tick_rate.is_finite() && self.tick_rate > 0.0) {
+            return invalid(format!(
+                "tick_rate must be more than zero, not {}",
+                self.tick_rate
+            ));
+        }
+        if !(self.send_rate.is_finite() && self.send_rate > 0.0) {
+            return invalid(format!(
+                "send_rate must be more than zero, not {}",
+                self.send_rate
+            ));
+        }
+        if self.max_rewind > MAX_REWIND_LIMIT {
+            return invalid(format!(
+                "max_rewind must be at most {} milliseconds, not {}",
+                MAX_REWIND_LIMIT, self.max_rewind
+            ));
+        }
+        // Clients can't connect to a wildcard address
+        if let Some(address) = self
+            .public_addresses
+            .iter()
+            .find(|address| address.ip().is_unspecified() || address.port() == 0)
+        {
+            return invalid(format!(
+                "public address {} must have a specific IP and port",
+                address
+            ));
+        }
+
+        self.interest.validate().map_err(ConfigError::Invalid)?;
+        self.rules.validate().map_err(ConfigError::Invalid)
+    }
+}
+
+/// Command line options of the server. Any option given overrides the config
+/// file.
+#[derive(clap::Args, Debug)]
+pub struct ServerArgs {
+    /// TOML file to read the settings from before applying the other options.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Port to listen on, keeping the IP of the bind address.
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    #[arg(long)]
+    bind_address: Option<SocketAddr>,
+
+    /// Address clients connect to. Can be given several times.
+    #[arg(long)]
+    public_address: Vec<SocketAddr>,
+
+    #[arg(long)]
+    max_clients: Option<usize>,
+
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// RON file of the map to play on.
+    #[arg(long)]
+    map: Option<PathBuf>,
+
+    /// RON file of the weapons players can choose from.
+    #[arg(long)]
+    weapons: Option<PathBuf>,
+
+    #[arg(long)]
+    bots: Option<usize>,
+
+    #[arg(long)]
+    tick_rate: Option<f64>,
+
+    #[arg(long)]
+    send_rate: Option<f64>,
+
+    /// Furthest back in time hits are judged against, in milliseconds.
+    #[arg(long)]
+    max_rewind: Option<u64>,
+
+    /// Distance beyond which players and bullets are not sent to a client.
+    #[arg(long)]
+    interest_radius: Option<f32>,
+
+    #[arg(long)]
+    player_speed: Option<f32>,
+
+    #[arg(long)]
+    max_health: Option<f32>,
+
+    /// Seconds dead players wait before respawning.
+    #[arg(long)]
+    respawn_delay: Option<f32>,
+
+    #[arg(long, value_enum)]
+    mode: Option<GameMode>,
+
+    /// Kills needed to win a match, or zero for no limit.
+    #[arg(long)]
+    score_limit: Option<u32>,
+
+    /// Seconds a match lasts, or zero for no limit.
+    #[arg(long)]
+    time_limit: Option<f32>,
+
+    /// Number of players, counting bots, needed to start a match.
+    #[arg(long)]
+    min_players: Option<usize>,
+}
+
+impl ServerArgs {
+    /// Reads the config file, if any, applies the command line overrides and
+    /// validates the result.
+    pub fn into_settings(self) -> Result<ServerSettings, ConfigError> {
+        let mut settings = match &self.config {
+            Some(path) => load(path)?,
+            None => ServerSettings::default(),
+        };
+
+        if let Some(bind_address) = self.bind_address {
+            settings.bind_address = bind_address;
+        }
+        if let Some(port) = self.port {
+            settings.bind_address.set_port(port);
+        }
+        if !self.public_address.is_empty() {
+            settings.public_addresses = self.public_address;
+        }
+        if let Some(max_clients) = self.max_clients {
+            settings.max_clients = max_clients;
+        }
+        if let Some(key_file) = self.key_file {
+            settings.key_file = Some(key_file);
+        }
+        if let Some(map) = self.map {
+            settings.map = Some(map);
+        }
+        if let Some(weapons) = self.weapons {
+            settings.weapons = Some(weapons);
+        }
+        if let Some(bots) = self.bots {
+            settings.bots = bots;
+        }
+        if let Some(tick_rate) = self.tick_rate {
+            settings.tick_rate = tick_rate;
+        }
+        if let Some(send_rate) = self.send_rate {
+            settings.send_rate = send_rate;
+        }
+        if let Some(max_rewind) = self.max_rewind {
+            settings.max_rewind = max_rewind;
+        }
+        if let Some(interest_radius) = self.interest_radius {
+            settings.interest.radius = interest_radius;
+        }
+        if let Some(player_speed) = self.player_speed {
+            settings.rules.player_speed = player_speed;
+        }
+        if let Some(max_health) = self.max_health {
+            settings.rules.max_health = max_health;
+        }
+        if let Some(respawn_delay) = self.respawn_delay {
+            settings.rules.respawn_delay = respawn_delay;
+        }
+        if let Some(mode) = self.mode {
+            settings.rules.mode = mode;
+        }
+        if let Some(score_limit) = self.score_limit {
+            settings.rules.score_limit = score_limit;
+        }
+        if let Some(time_limit) = self.time_limit {
+            settings.rules.time_limit = time_limit;
+        }
+        if let Some(min_players) = self.min_players {
+            settings.rules.min_players = min_players;
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/// Everything a client can be configured with. Like the server, settings are
+/// read from a TOML file and can be overridden with `ClientArgs`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    pub server_address: SocketAddr,
+
+    /// Connect token to join a secure server with. The server address and
+    /// player name are taken from the token.
+    pub token: Option<PathBuf>,
+
+    /// Name other players see. Servers without authentication show the
+    /// client ID instead if this is not set.
+    pub name: Option<String>,
+
+    pub window: WindowSettings,
+
+    /// Number of pixels per world unit.
+    pub zoom: f32,
+
+    /// How far behind the latest snapshots other players are shown, in
+    /// milliseconds. Larger delays hide more network jitter and packet loss.
+    pub interpolation_delay: u64,
+
+    /// Number of messages from the server that may fail to decode within
+    /// `decode_error_window` before the client disconnects.
+    pub max_decode_errors: u32,
+
+    /// Length of the window that decoding failures are counted over, in
+    /// milliseconds.
+    pub decode_error_window: u64,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            server_address: SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT)),
+            token: None,
+            name: None,
+            window: WindowSettings::default(),
+            zoom: 20.0,
+            interpolation_delay: 100,
+            max_decode_errors: 10,
+            decode_error_window: 10_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowSettings {
+    pub width: f32,
+    pub height: f32,
+    pub fullscreen: bool,
+    pub vsync: bool,
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        Self {
+            width: 1280.0,
+            height: 720.0,
+            fullscreen: false,
+            vsync: true,
+        }
+    }
+}
+
+impl ClientSettings {
+    /// Checks the settings for values the client can't run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |problem: String| Err(ConfigError::Invalid(problem));
+
+        if let Some(name) = &self.name {
+            // The name is sent with its length in the first byte of the
+            // connection's user data
+            if name.trim().is_empty() {
+                return invalid("name can't be empty".to_string());
+            }
+            if name.len() >= NETCODE_USER_DATA_BYTES {
+                return invalid(format!(
+                    "name must be shorter than {} bytes",
+                    NETCODE_USER_DATA_BYTES
+                ));
+            }
+        }
+        if !(self.window.width >= 1.0 && self.window.height >= 1.0) {
+            return invalid(format!(
+                "window size must be at least 1x1, not {}x{}",
+                self.window.width, self.window.height
+            ));
+        }
+        if !(self.zoom.is_finite() && self.zoom > 0.0) {
+            return invalid(format!("zoom must be more than zero, not {}", self.zoom));
+        }
+        if self.decode_error_window == 0 {
+            return invalid("decode_error_window must be more than zero".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Command line options of the client. Any option given overrides the config
+/// file.
+#[derive(clap::Args, Debug)]
+pub struct ClientArgs {
+    /// TOML file to read the settings from before applying the other options.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    #[arg(short, long)]
+    server_address: Option<SocketAddr>,
+
+    /// Connect token to join a secure server with. The server address and
+    /// player name are taken from the token.
+    #[arg(long)]
+    token: Option<PathBuf>,
+
+    /// Name other players see.
+    #[arg(short, long)]
+    name: Option<String>,
+
+    #[arg(long)]
+    width: Option<f32>,
+
+    #[arg(long)]
+    height: Option<f32>,
+
+    #[arg(long)]
+    fullscreen: Option<bool>,
+
+    #[arg(long)]
+    vsync: Option<bool>,
+
+    /// Number of pixels per world unit.
+    #[arg(long)]
+    zoom: Option<f32>,
+
+    /// How far behind the latest snapshots other players are shown, in
+    /// milliseconds.
+    #[arg(long)]
+    interpolation_delay: Option<u64>,
+
+    /// Number of malformed messages from the server, within the decode error
+    /// window, that the client disconnects after.
+    #[arg(long)]
+    max_decode_errors: Option<u32>,
+
+    /// Window that malformed messages are counted over, in milliseconds.
+    #[arg(long)]
+    decode_error_window: Option<u64>,
+}
+
+impl ClientArgs {
+    /// Reads the config file, if any, applies the command line overrides and
+    /// validates the result.
+    pub fn into_settings(self) -> Result<ClientSettings, ConfigError> {
+        let mut settings = match &self.config {
+            Some(path) => load(path)?,
+            None => ClientSettings::default(),
+        };
+
+        if let Some(server_address) = self.server_address {
+            settings.server_address = server_address;
+        }
+        if let Some(token) = self.token {
+            settings.token = Some(token);
+        }
+        if let Some(name) = self.name {
+            settings.name = Some(name);
+        }
+        if let Some(width) = self.width {
+            settings.window.width = width;
+        }
+        if let Some(height) = self.height {
+            settings.window.height = height;
+        }
+        if let Some(fullscreen) = self.fullscreen {
+            settings.window.fullscreen = fullscreen;
+        }
+        if let Some(vsync) = self.vsync {
+            settings.window.vsync = vsync;
+        }
+        if let Some(zoom) = self.zoom {
+            settings.zoom = zoom;
+        }
+        if let Some(interpolation_delay) = self.interpolation_delay {
+            settings.interpolation_delay = interpolation_delay;
+        }
+        if let Some(max_decode_errors) = self.max_decode_errors {
+            settings.max_decode_errors = max_decode_errors;
+        }
+        if let Some(decode_error_window) = self.decode_error_window {
+            settings.decode_error_window = decode_error_window;
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+}
@@ -0,0 +1,337 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy_renet::renet::RenetServer;
+use renet::ClientId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    channels::ServerChannel,
+    clock::epoch_millis,
+    health::{Dead, DeathEvent, Health, RespawnTimer, SpawnPoints},
+    messages::ServerMessage,
+    player_controller::PlayerController,
+    rules::{GameMode, GameRules},
+    server::{PlayerClient, PlayerMessages},
+    weapon::{Weapon, Weapons},
+    GameState,
+};
+
+/// Side a player fights on in team deathmatch.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Team {
+    Red,
+    Blue,
+}
+
+impl Team {
+    const ALL: [Self; 2] = [Self::Red, Self::Blue];
+
+    pub fn color(self) -> Color {
+        match self {
+            Self::Red => Color::rgb(1.0, 0.45, 0.4),
+            Self::Blue => Color::rgb(0.45, 0.65, 1.0),
+        }
+    }
+}
+
+impl fmt::Display for Team {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Red => write!(f, "Red"),
+            Self::Blue => write!(f, "Blue"),
+        }
+    }
+}
+
+/// Kills and deaths of a player in the current match. Only exists on the
+/// server.
+#[derive(Component, Default)]
+pub struct Score {
+    kills: u32,
+    deaths: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerScore {
+    pub client_id: ClientId,
+    pub team: Option<Team>,
+    pub kills: u32,
+    pub deaths: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Winner {
+    Player(ClientId),
+    Team(Team),
+    Draw,
+}
+
+/// State of the match. The server sends it to every client whenever it
+/// changes.
+#[derive(Resource, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MatchStatus {
+    pub state: GameState,
+
+    /// When the current phase ends, in milliseconds since epoch on the server.
+    /// Phases that wait for players don't end on their own.
+    pub ends_at: Option<u64>,
+
+    /// Scores of every player, best first.
+    pub scores: Vec<PlayerScore>,
+
+    /// Winner of the last match, kept until the next one starts.
+    pub winner: Option<Winner>,
+}
+
+impl MatchStatus {
+    pub fn team(&self, client_id: ClientId) -> Option<Team> {
+        self.scores
+            .iter()
+            .find(|score| score.client_id == client_id)
+            .and_then(|score| score.team)
+    }
+
+    /// Kills of everyone competing for the win, best first. Players compete in
+    /// deathmatch, and teams in team deathmatch.
+    pub fn standings(&self, mode: GameMode) -> Vec<(Winner, u32)> {
+        let mut standings: Vec<_> = match mode {
+            GameMode::Deathmatch => self
+                .scores
+                .iter()
+                .map(|score| (Winner::Player(score.client_id), score.kills))
+                .collect(),
+            GameMode::TeamDeathmatch => Team::ALL
+                .into_iter()
+                .map(|team| {
+                    let kills = self
+                        .scores
+                        .iter()
+                        .filter(|score| score.team == Some(team))
+                        .map(|score| score.kills)
+                        .sum();
+                    (Winner::Team(team), kills)
+                })
+                .collect(),
+        };
+        standings.sort_by(|(_, a), (_, b)| b.cmp(a));
+        standings
+    }
+
+    /// Who is ahead and by how many kills, which is a draw if the best are
+    /// tied.
+    fn leader(&self, mode: GameMode) -> Option<(Winner, u32)> {
+        match self.standings(mode).as_slice() {
+            [] => None,
+            [(_, first), (_, second), ..] if first == second => Some((Winner::Draw, *first)),
+            [first, ..] => Some(*first),
+        }
+    }
+}
+
+/// Whether players may shoot. Weapons are put away while a match is about to
+/// start and after it ended.
+pub fn weapons_allowed(state: Res<State<GameState>>) -> bool {
+    matches!(state.get(), GameState::Warmup | GameState::InProgress)
+}
+
+/// Gives players who just joined a score, and in team deathmatch puts them on
+/// the smaller team.
+pub fn join_match(
+    mut commands: Commands,
+    rules: Res<GameRules>,
+    new_players: Query<Entity, Added<PlayerClient>>,
+    teams: Query<&Team>,
+) {
+    let mut red = teams.iter().filter(|team| **team == Team::Red).count();
+    let mut blue = teams.iter().count() - red;
+
+    for entity in new_players.iter() {
+        let mut player = commands.entity(entity);
+        player.insert(Score::default());
+
+        if rules.mode != GameMode::TeamDeathmatch {
+            continue;
+        }
+        let team = if red <= blue {
+            red += 1;
+            Team::Red
+        } else {
+            blue += 1;
+            Team::Blue
+        };
+        player.insert(team);
+    }
+}
+
+/// Counts kills and deaths while a match is in progress.
+pub fn count_kills(
+    state: Res<State<GameState>>,
+    mut deaths: EventReader<DeathEvent>,
+    mut scores: Query<&mut Score>,
+) {
+    // Deaths outside of a match are read anyway, so that they aren't counted
+    // once it starts
+    if *state.get() != GameState::InProgress {
+        deaths.clear();
+        return;
+    }
+
+    for death in deaths.read() {
+        if let Ok(mut score) = scores.get_mut(death.victim) {
+            score.deaths += 1;
+        }
+        let Some(killer) = death.killer.filter(|killer| *killer != death.victim) else {
+            continue;
+        };
+        if let Ok(mut score) = scores.get_mut(killer) {
+            score.kills += 1;
+        }
+    }
+}
+
+/// Copies the scores of the players into the match status when they change.
+pub fn update_scores(
+    mut status: ResMut<MatchStatus>,
+    players: Query<(&PlayerClient, &Score, Option<&Team>)>,
+    changed_scores: Query<(), Changed<Score>>,
+    mut removed_players: RemovedComponents<PlayerClient>,
+) {
+    // Removals are always read, so that old ones don't trigger an update later
+    let players_left = removed_players.read().count() > 0;
+    if changed_scores.is_empty() && !players_left {
+        return;
+    }
+
+    let mut scores: Vec<_> = players
+        .iter()
+        .map(|(player_client, score, team)| PlayerScore {
+            client_id: **player_client,
+            team: team.copied(),
+            kills: score.kills,
+            deaths: score.deaths,
+        })
+        .collect();
+    scores.sort_by(|a, b| b.kills.cmp(&a.kills).then(a.deaths.cmp(&b.deaths)));
+    status.scores = scores;
+}
+
+/// Moves the match on to its next phase once the current one is over.
+pub fn advance_match(
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut status: ResMut<MatchStatus>,
+    rules: Res<GameRules>,
+    players: Query<(), With<PlayerClient>>,
+) {
+    let now = epoch_millis();
+    let player_count = players.iter().count();
+    let enough_players = player_count >= rules.min_players;
+    let phase_over = status.ends_at.is_some_and(|ends_at| now >= ends_at);
+
+    let next = match state.get() {
+        GameState::Loading => GameState::Warmup,
+        GameState::Warmup if enough_players => GameState::Countdown,
+        GameState::Countdown if !enough_players => GameState::Warmup,
+        GameState::Countdown if phase_over => GameState::InProgress,
+        GameState::InProgress => {
+            let leader = status.leader(rules.mode);
+            let score_reached = rules.score_limit > 0
+                && leader.is_some_and(|(winner, kills)| {
+                    winner != Winner::Draw && kills >= rules.score_limit
+                });
+            if !score_reached && !phase_over {
+                return;
+            }
+            status.winner = Some(leader.map_or(Winner::Draw, |(winner, _)| winner));
+            GameState::PostMatch
+        }
+        GameState::PostMatch if phase_over => GameState::Warmup,
+        _ => return,
+    };
+
+    let after = |seconds: f32| Some(now.saturating_add((seconds * 1000.0) as u64));
+    status.ends_at = match next {
+        GameState::Countdown => after(rules.countdown),
+        GameState::InProgress if rules.time_limit > 0.0 => after(rules.time_limit),
+        GameState::PostMatch => after(rules.post_match_delay),
+        _ => None,
+    };
+
+    match next {
+        GameState::InProgress => {
+            println!("Match started with {} players.", player_count);
+            status.winner = None;
+
+            // Scores are reset when the match starts, which clients shouldn't
+            // have to wait for
+            for score in status.scores.iter_mut() {
+                score.kills = 0;
+                score.deaths = 0;
+            }
+        }
+        GameState::PostMatch => match status.winner {
+            Some(Winner::Player(client_id)) => println!("Match won by player {}.", client_id),
+            Some(Winner::Team(team)) => println!("Match won by the {} team.", team),
+            _ => println!("Match ended in a draw."),
+        },
+        _ => {}
+    }
+    status.state = next;
+    next_state.set(next);
+}
+
+/// Tells every client about changes to the match. Clients that just joined are
+/// sent the status during the handshake.
+pub fn broadcast_match_status(mut server: ResMut<RenetServer>, status: Res<MatchStatus>) {
+    if !status.is_changed() {
+        return;
+    }
+
+    match bincode::serialize(&ServerMessage::MatchStatus(status.clone())) {
+        Ok(bytes) => server.broadcast_message(ServerChannel::ServerMessages, bytes),
+        Err(err) => warn!("Failed to serialize match status message: {}", err),
+    }
+}
+
+/// Clears the scores and respawns everyone with full health and ammo, so that
+/// every match starts even.
+pub fn start_match(
+    mut commands: Commands,
+    mut player_messages: PlayerMessages,
+    weapons: Res<Weapons>,
+    spawn_points: Res<SpawnPoints>,
+    mut players: Query<(
+        Entity,
+        &PlayerClient,
+        &mut Score,
+        &mut Health,
+        &mut PlayerController,
+        &mut Transform,
+    )>,
+) {
+    for (entity, player_client, mut score, mut health, mut controller, mut transform) in
+        players.iter_mut()
+    {
+        *score = Score::default();
+        health.current = health.max;
+        controller.velocity = Vec2::ZERO;
+        let position = spawn_points.choose();
+        transform.translation = position.extend(transform.translation.z);
+        commands
+            .entity(entity)
+            .remove::<(Dead, RespawnTimer)>()
+            .insert(Weapon::new(&weapons));
+
+        let bytes = match bincode::serialize(&ServerMessage::PlayerRespawned {
+            client_id: **player_client,
+        }) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Failed to serialize respawn message: {}", err);
+                continue;
+            }
+        };
+        player_messages.send(**player_client, bytes);
+    }
+}
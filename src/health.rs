@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use rand::seq::SliceRandom;
+
+use crate::{
+    game_match::Team,
+    messages::ServerMessage,
+    player_controller::PlayerController,
+    rules::GameRules,
+    server::{PlayerClient, PlayerMessages},
+};
+
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+
+    pub fn fraction(&self) -> f32 {
+        (self.current / self.max).clamp(0.0, 1.0)
+    }
+}
+
+/// Marks a player that has died and not respawned yet. Dead players can not
+/// move, shoot or be hit.
+#[derive(Component, Default)]
+pub struct Dead;
+
+/// Counts down until a dead player respawns. Only exists on the server.
+#[derive(Component, Deref, DerefMut)]
+pub struct RespawnTimer(Timer);
+
+/// Sent on the server whenever a player is damaged.
+#[derive(Event)]
+pub struct DamageEvent {
+    pub target: Entity,
+    pub attacker: Entity,
+    pub amount: f32,
+}
+
+/// Sent on the server whenever a player dies.
+#[derive(Event)]
+pub struct DeathEvent {
+    pub victim: Entity,
+
+    /// Player whose damage was fatal, if they are still around.
+    pub killer: Option<Entity>,
+}
+
+/// Positions players are spawned at.
+#[derive(Resource, Deref, DerefMut)]
+pub struct SpawnPoints(Vec<Vec2>);
+
+impl SpawnPoints {
+    pub fn new(spawn_points: Vec<Vec2>) -> Self {
+        Self(spawn_points)
+    }
+
+    pub fn choose(&self) -> Vec2 {
+        self.0
+            .choose(&mut rand::thread_rng())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+pub fn apply_damage(
+    mut commands: Commands,
+    mut player_messages: PlayerMessages,
+    rules: Res<GameRules>,
+    mut events: EventReader<DamageEvent>,
+    mut deaths: EventWriter<DeathEvent>,
+    mut targets: Query<(&mut Health, &PlayerClient, Option<&Team>), Without<Dead>>,
+    attackers: Query<(&PlayerClient, Option<&Team>)>,
+) {
+    for event in events.read() {
+        let Ok((mut health, target_client, team)) = targets.get_mut(event.target) else {
+            continue;
+        };
+        let attacker = attackers.get(event.attacker).ok();
+
+        // Teammates can't hurt each other
+        if team.is_some() && attacker.is_some_and(|(_, attacker_team)| attacker_team == team) {
+            continue;
+        }
+
+        // Several hits may land in the same tick, so only the one that crosses
+        // zero kills the player
+        let was_alive = health.current > 0.0;
+        health.current = (health.current - event.amount).max(0.0);
+
+        let mut messages = vec![ServerMessage::PlayerDamaged {
+            client_id: **target_client,
+            health: health.current,
+        }];
+
+        if was_alive && health.current <= 0.0 {
+            commands.entity(event.target).insert((
+                Dead,
+                RespawnTimer(Timer::new(
+                    Duration::from_secs_f32(rules.respawn_delay),
+                    TimerMode::Once,
+                )),
+            ));
+            messages.push(ServerMessage::PlayerDied {
+                client_id: **target_client,
+                killer: attacker.map(|(attacker_client, _)| **attacker_client),
+            });
+            deaths.send(DeathEvent {
+                victim: event.target,
+                killer: attacker.is_some().then_some(event.attacker),
+            });
+        }
+
+        for message in messages {
+            let bytes = match bincode::serialize(&message) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    warn!("Failed to serialize damage message: {}", err);
+                    continue;
+                }
+            };
+            player_messages.send(**target_client, bytes);
+        }
+    }
+}
+
+pub fn respawn_players(
+    mut commands: Commands,
+    mut player_messages: PlayerMessages,
+    time: Res<Time>,
+    spawn_points: Res<SpawnPoints>,
+    mut players: Query<(
+        Entity,
+        &mut RespawnTimer,
+        &mut Health,
+        &mut PlayerController,
+        &mut Transform,
+        &PlayerClient,
+    )>,
+) {
+    for (entity, mut timer, mut health, mut controller, mut transform, player_client) in
+        players.iter_mut()
+    {
+        if !timer.tick(time.delta()).finished() {
+            continue;
+        }
+
+        let position = spawn_points.choose();
+        transform.translation = position.extend(transform.translation.z);
+        controller.velocity = Vec2::ZERO;
+        health.current = health.max;
+        commands.entity(entity).remove::<(Dead, RespawnTimer)>();
+
+        let bytes = match bincode::serialize(&ServerMessage::PlayerRespawned {
+            client_id: **player_client,
+        }) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Failed to serialize respawn message: {}", err);
+                continue;
+            }
+        };
+        player_messages.send(**player_client, bytes);
+    }
+}
@@ -0,0 +1,118 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::ClientId;
+use serde::Deserialize;
+
+/// Which players each client is sent, read from the `interest` table of the
+/// server config.
+#[derive(Resource, Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterestSettings {
+    /// Players and bullets further than this from a client's own player are
+    /// not sent to it, and neither are the damage and deaths of those players.
+    /// The default covers the client's view with some margin.
+    pub radius: f32,
+
+    /// Players within this distance are sent on every tick.
+    pub full_rate_radius: f32,
+
+    /// Fraction of ticks that players at the edge of `radius` are sent on.
+    pub min_priority: f32,
+}
+
+impl Default for InterestSettings {
+    fn default() -> Self {
+        Self {
+            radius: 40.0,
+            full_rate_radius: 15.0,
+            min_priority: 0.25,
+        }
+    }
+}
+
+impl InterestSettings {
+    /// Checks that the settings send every client at least the players close
+    /// to it, returning a readable description of the first problem otherwise.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.radius.is_finite() && self.radius > 0.0) {
+            return Err(format!(
+                "interest radius must be more than zero, not {}",
+                self.radius
+            ));
+        }
+        if !(self.full_rate_radius.is_finite()
+            && (0.0..=self.radius).contains(&self.full_rate_radius))
+        {
+            return Err(format!(
+                "interest full_rate_radius must be from 0 to the radius, not {}",
+                self.full_rate_radius
+            ));
+        }
+        if !(self.min_priority > 0.0 && self.min_priority <= 1.0) {
+            return Err(format!(
+                "interest min_priority must be more than 0 and at most 1, not {}",
+                self.min_priority
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fraction of ticks that a player `distance` away should be sent on, or
+    /// zero if it should not be sent at all.
+    pub fn priority(&self, distance: f32) -> f32 {
+        if distance > self.radius {
+            return 0.0;
+        }
+        if distance <= self.full_rate_radius {
+            return 1.0;
+        }
+
+        let falloff = (distance - self.full_rate_radius) / (self.radius - self.full_rate_radius);
+        1.0 + (self.min_priority - 1.0) * falloff
+    }
+}
+
+/// Accumulated priority of every player relevant to a client. A player is sent
+/// whenever its accumulator reaches one, so low priority players are sent on
+/// fewer ticks without any of them being starved.
+#[derive(Default)]
+pub struct PriorityAccumulator {
+    priorities: HashMap<ClientId, f32>,
+
+    /// Players that became relevant since they were last taken.
+    entered: Vec<ClientId>,
+}
+
+impl PriorityAccumulator {
+    /// Adds a tick's worth of priority for a player and returns whether it
+    /// should be sent on this tick. Players that just became relevant are sent
+    /// right away.
+    pub fn accumulate(&mut self, client_id: ClientId, priority: f32) -> bool {
+        let accumulated = self.priorities.entry(client_id).or_insert_with(|| {
+            self.entered.push(client_id);
+            1.0
+        });
+        *accumulated += priority;
+
+        if *accumulated >= 1.0 {
+            *accumulated = (*accumulated - 1.0).min(1.0);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets players that are no longer relevant.
+    pub fn retain(&mut self, mut relevant: impl FnMut(&ClientId) -> bool) {
+        self.priorities.retain(|client_id, _| relevant(client_id));
+    }
+
+    /// Whether a player is relevant to the client.
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.priorities.contains_key(&client_id)
+    }
+
+    /// Players that became relevant since this was last called.
+    pub fn take_entered(&mut self) -> Vec<ClientId> {
+        std::mem::take(&mut self.entered)
+    }
+}
@@ -0,0 +1,76 @@
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::prelude::*;
+
+use crate::{
+    player_controller::PlayerInput,
+    tick::{Tick, TickRate},
+};
+
+/// Set from `ServerSettings::max_rewind`.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct LagCompensationSettings {
+    /// Furthest back in time hits are judged against. Clients with more latency
+    /// than this have to lead their shots.
+    pub max_rewind: Duration,
+}
+
+impl LagCompensationSettings {
+    pub fn max_rewind_ticks(&self, tick_rate: TickRate) -> u32 {
+        tick_rate.ticks(self.max_rewind).ceil() as u32
+    }
+
+    /// Number of ticks a shooter's view of the world lags behind `tick`, based
+    /// on the last snapshot they received and how far they interpolate behind
+    /// it.
+    pub fn rewind_ticks(&self, tick: Tick, input: &PlayerInput, tick_rate: TickRate) -> u32 {
+        let delay = Duration::from_millis(input.interpolation_delay_ms as u64);
+        let delay_ticks = tick_rate.ticks(delay).round() as u32;
+        let view_tick = input.snapshot_tick.saturating_sub(delay_ticks);
+
+        tick.saturating_sub(view_tick)
+            .min(self.max_rewind_ticks(tick_rate))
+    }
+}
+
+/// Number of ticks a player's view of other players lags behind the server.
+/// Updated from every input the server applies.
+#[derive(Component, Default, Deref, DerefMut)]
+pub struct ViewRewind(pub u32);
+
+/// Server-side record of where a player was on recent ticks, oldest first.
+#[derive(Component, Default)]
+pub struct PositionHistory {
+    positions: VecDeque<(Tick, Vec2)>,
+}
+
+impl PositionHistory {
+    /// Position on `tick`, or the oldest known position if the tick is no
+    /// longer recorded.
+    pub fn position_at(&self, tick: Tick) -> Option<Vec2> {
+        self.positions
+            .iter()
+            .rev()
+            .find(|(recorded_tick, _)| *recorded_tick <= tick)
+            .or(self.positions.front())
+            .map(|(_, position)| *position)
+    }
+}
+
+pub fn record_positions(
+    tick: Res<Tick>,
+    settings: Res<LagCompensationSettings>,
+    tick_rate: Res<TickRate>,
+    mut players: Query<(&Transform, &mut PositionHistory)>,
+) {
+    let capacity = settings.max_rewind_ticks(*tick_rate) as usize + 1;
+
+    for (transform, mut history) in players.iter_mut() {
+        while history.positions.len() >= capacity {
+            history.positions.pop_front();
+        }
+        history
+            .positions
+            .push_back((*tick, transform.translation.xy()));
+    }
+}
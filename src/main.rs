@@ -1,27 +1,58 @@
+mod ai;
+mod auth;
+mod bots;
+mod bullet;
 mod camera_controller;
 mod channels;
 mod client;
+mod clock;
+mod config;
+mod game_match;
+mod health;
+mod interest;
+mod lag_compensation;
+mod map;
 mod messages;
 mod player;
 mod player_controller;
+mod prediction;
 mod remote_state;
 mod rendering;
+mod replication;
+mod rules;
 mod server;
+mod snapshot;
+mod tick;
+mod weapon;
 
-use std::net::SocketAddr;
+use std::{io, net::SocketAddr, path::PathBuf, process};
 
 use bevy::prelude::*;
 
 use clap::Parser;
 use client::run_client;
+use config::{ClientArgs, ServerArgs};
+use serde::{Deserialize, Serialize};
 use server::{make_connection_config, run_server};
 
-#[derive(Clone, PartialEq, Eq, Debug, Hash, Default, States)]
+/// Phase of the match. The server drives it, and clients follow the phase the
+/// server sends them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Default, States, Serialize, Deserialize)]
 pub enum GameState {
+    /// Clients have not heard from the server yet.
     #[default]
     Loading,
-    InGame,
-    Paused,
+
+    /// Waiting for enough players to start a match. Kills don't count.
+    Warmup,
+
+    /// Counting down to the start of a match. Nobody can shoot.
+    Countdown,
+
+    InProgress,
+
+    /// Showing the results of the match that just ended. Nobody can shoot.
+    PostMatch,
 }
 
 #[derive(clap::Parser)]
@@ -32,26 +63,140 @@ struct Cli {
 
 #[derive(clap::Subcommand)]
 enum Subcommand {
-    Server {
-        #[arg(short, long, default_value = "20987")]
-        port: u16,
-    },
-    Client {
+    Server(ServerArgs),
+    Client(ClientArgs),
+    /// Connects headless bots to a server to load test it.
+    Bots {
         #[arg(short, long, default_value = "127.0.0.1:20987")]
         server_address: SocketAddr,
+
+        #[arg(short = 'n', long, default_value = "16")]
+        count: usize,
+
+        /// Private key to issue the bots connect tokens with, for secure
+        /// servers.
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// Generates a private key for the server and token issuer.
+    Keygen {
+        #[arg(long, default_value = "server.key")]
+        key_file: PathBuf,
+
+        /// Replace the key file if it already exists, which invalidates the
+        /// tokens issued with the old key.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Issues a connect token for a user.
+    Token {
+        username: String,
+
+        #[arg(long, default_value = "server.key")]
+        key_file: PathBuf,
+
+        /// Addresses of the servers the token is valid for.
+        #[arg(short, long, default_value = "127.0.0.1:20987")]
+        server_address: Vec<SocketAddr>,
+
+        /// Where to write the token.
+        #[arg(short, long, default_value = "client.token")]
+        output: PathBuf,
     },
 }
 
+/// Exits with an error message if `result` is an error.
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>, context: &str) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}: {}", context, err);
+        process::exit(1);
+    })
+}
+
 fn main() {
     let cli = Cli::parse();
     let connection_config = make_connection_config();
 
     match cli.subcommand {
-        Subcommand::Server { port } => {
-            run_server(port, connection_config);
+        Subcommand::Server(args) => {
+            let settings = or_exit(args.into_settings(), "Failed to configure server");
+            let private_key = settings.key_file.as_ref().map(|key_file| {
+                or_exit(
+                    auth::load_private_key(key_file),
+                    "Failed to load private key",
+                )
+            });
+            let map = match &settings.map {
+                Some(path) => or_exit(
+                    map::Map::load(path, player_controller::PLAYER_RADIUS),
+                    &format!("Failed to load {}", path.display()),
+                ),
+                None => map::Map::default(),
+            };
+            let weapons = match &settings.weapons {
+                Some(path) => or_exit(
+                    weapon::Weapons::load(path),
+                    &format!("Failed to load {}", path.display()),
+                ),
+                None => weapon::Weapons::default(),
+            };
+            run_server(settings, map, weapons, private_key, connection_config);
+        }
+        Subcommand::Client(args) => {
+            let settings = or_exit(args.into_settings(), "Failed to configure client");
+            let connect_token = settings
+                .token
+                .as_ref()
+                .map(|token| or_exit(auth::read_token(token), "Failed to read token"));
+            if connect_token.is_some() && settings.name.is_some() {
+                println!("Ignoring the name, since the token decides the player's name");
+            }
+            run_client(settings, connect_token, connection_config);
+        }
+        Subcommand::Bots {
+            server_address,
+            count,
+            key_file,
+        } => {
+            let private_key = key_file.map(|key_file| {
+                or_exit(
+                    auth::load_private_key(&key_file),
+                    "Failed to load private key",
+                )
+            });
+            bots::run_bots(server_address, count, private_key, connection_config);
+        }
+        Subcommand::Keygen { key_file, force } => {
+            let result = auth::generate_private_key(&key_file, force);
+            if result
+                .as_ref()
+                .is_err_and(|err| err.kind() == io::ErrorKind::AlreadyExists)
+            {
+                eprintln!(
+                    "{} already exists. Pass --force to replace it.",
+                    key_file.display()
+                );
+                process::exit(1);
+            }
+            or_exit(result, "Failed to write private key");
+            println!("Wrote private key to {}", key_file.display());
         }
-        Subcommand::Client { server_address } => {
-            run_client(server_address, connection_config);
+        Subcommand::Token {
+            username,
+            key_file,
+            server_address,
+            output,
+        } => {
+            let private_key = or_exit(
+                auth::load_private_key(&key_file),
+                "Failed to load private key",
+            );
+            let token = or_exit(
+                auth::generate_token(&private_key, &username, server_address),
+                "Failed to generate token",
+            );
+            or_exit(auth::write_token(&output, &token), "Failed to write token");
+            println!("Wrote token for {} to {}", username, output.display());
         }
     }
 }
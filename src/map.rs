@@ -0,0 +1,463 @@
+use std::{fmt, fs, io, path::Path};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{player_controller::PLAYER_RADIUS, snapshot::MAX_QUANTIZED_POSITION};
+
+/// Rays only hit the bounds after travelling at least this fraction of their
+/// length, so that rays starting on the edge of the map can leave it.
+const RAY_EPSILON: f32 = 1e-4;
+
+/// Number of times collisions are resolved per step. Resolving a collision can
+/// push a player into a neighbouring wall, which the next pass fixes.
+const COLLISION_PASSES: usize = 2;
+
+#[derive(Debug)]
+pub enum MapError {
+    Read(io::Error),
+    Parse(ron::error::SpannedError),
+    Invalid(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Read(err) => write!(f, "failed to read map: {}", err),
+            Self::Parse(err) => write!(f, "invalid map: {}", err),
+            Self::Invalid(problem) => write!(f, "invalid map: {}", problem),
+        }
+    }
+}
+
+/// Where a ray hit the map.
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    /// Fraction of the ray's length travelled before the hit.
+    pub fraction: f32,
+
+    /// Direction pointing out of the surface that was hit.
+    pub normal: Vec2,
+
+    /// Index of the wall that was hit, or `None` for the bounds.
+    pub wall: Option<usize>,
+}
+
+/// Axis-aligned box that players can not pass through. Obstacles inside the
+/// arena are walls as well.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Wall {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Wall {
+    fn contains(&self, point: Vec2, margin: f32) -> bool {
+        point.cmpge(self.min - margin).all() && point.cmple(self.max + margin).all()
+    }
+
+    /// Fraction of the segment from `start` to `end` at which a circle moving
+    /// along it touches the wall, with the normal of the side it touches.
+    /// Segments starting inside the wall don't hit it.
+    fn raycast(&self, start: Vec2, end: Vec2, radius: f32) -> Option<(f32, Vec2)> {
+        let delta = end - start;
+        let min = self.min - radius;
+        let max = self.max + radius;
+
+        let mut enter = f32::NEG_INFINITY;
+        let mut exit = f32::INFINITY;
+        let mut normal = Vec2::ZERO;
+
+        for axis in 0..2 {
+            if delta[axis] == 0.0 {
+                // Parallel to this pair of sides, so it must already be
+                // between them
+                if start[axis] < min[axis] || start[axis] > max[axis] {
+                    return None;
+                }
+                continue;
+            }
+
+            let near = if delta[axis] > 0.0 {
+                min[axis]
+            } else {
+                max[axis]
+            };
+            let far = if delta[axis] > 0.0 {
+                max[axis]
+            } else {
+                min[axis]
+            };
+            let near_t = (near - start[axis]) / delta[axis];
+            let far_t = (far - start[axis]) / delta[axis];
+
+            if near_t > enter {
+                enter = near_t;
+                normal = Vec2::ZERO;
+                normal[axis] = -delta[axis].signum();
+            }
+            exit = exit.min(far_t);
+        }
+
+        ((0.0..=1.0).contains(&enter) && enter <= exit).then_some((enter, normal))
+    }
+
+    /// Pushes a circle out of the wall. Returns the direction it was pushed
+    /// in, if it overlapped.
+    fn push_out(&self, position: &mut Vec2, radius: f32) -> Option<Vec2> {
+        let closest = position.clamp(self.min, self.max);
+        let offset = *position - closest;
+
+        if offset != Vec2::ZERO {
+            let distance = offset.length();
+            if distance >= radius {
+                return None;
+            }
+            let normal = offset / distance;
+            *position = closest + normal * radius;
+            return Some(normal);
+        }
+
+        // The center is inside the wall, so leave through the nearest side
+        let exits = [
+            (self.min.x - position.x, Vec2::NEG_X),
+            (self.max.x - position.x, Vec2::X),
+            (self.min.y - position.y, Vec2::NEG_Y),
+            (self.max.y - position.y, Vec2::Y),
+        ];
+        let (distance, normal) = exits
+            .into_iter()
+            .min_by(|(a, _), (b, _)| a.abs().total_cmp(&b.abs()))?;
+        *position += normal * (distance.abs() + radius);
+        Some(normal)
+    }
+}
+
+/// Part of the floor drawn in another color, such as markings. Decorations
+/// don't collide with anything.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Decoration {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+/// Layout of the arena. The server loads it and sends it to clients when they
+/// join, so that both move players against the same walls.
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Map {
+    pub name: String,
+
+    /// Players can not leave this area.
+    pub bounds: Wall,
+
+    pub walls: Vec<Wall>,
+
+    #[serde(default)]
+    pub decorations: Vec<Decoration>,
+
+    pub spawn_points: Vec<Vec2>,
+}
+
+impl Default for Map {
+    /// The arena in `maps/arena.ron`, used when the server is not given a map.
+    fn default() -> Self {
+        Self::parse(include_str!("../maps/arena.ron"), PLAYER_RADIUS)
+            .expect("built-in map should be valid")
+    }
+}
+
+impl Map {
+    /// Reads a map from a RON file and checks that it can be played on.
+    pub fn load(path: &Path, player_radius: f32) -> Result<Self, MapError> {
+        let text = fs::read_to_string(path).map_err(MapError::Read)?;
+        Self::parse(&text, player_radius)
+    }
+
+    fn parse(text: &str, player_radius: f32) -> Result<Self, MapError> {
+        let map: Self = ron::from_str(text).map_err(MapError::Parse)?;
+        map.validate(player_radius).map_err(MapError::Invalid)?;
+        Ok(map)
+    }
+
+    fn validate(&self, player_radius: f32) -> Result<(), String> {
+        let is_valid =
+            |min: Vec2, max: Vec2| min.is_finite() && max.is_finite() && min.cmplt(max).all();
+
+        if !is_valid(self.bounds.min, self.bounds.max) {
+            return Err(format!(
+                "bounds must have a min smaller than their max, not {} and {}",
+                self.bounds.min, self.bounds.max
+            ));
+        }
+        if let Some(wall) = self.walls.iter().find(|wall| !is_valid(wall.min, wall.max)) {
+            return Err(format!(
+                "walls must have a min smaller than their max, not {} and {}",
+                wall.min, wall.max
+            ));
+        }
+
+        // Snapshots couldn't carry the positions of players further out
+        let in_range = |wall: &Wall| {
+            wall.min.cmpge(Vec2::splat(-MAX_QUANTIZED_POSITION)).all()
+                && wall.max.cmple(Vec2::splat(MAX_QUANTIZED_POSITION)).all()
+        };
+        if !in_range(&self.bounds) {
+            return Err(format!(
+                "bounds must be within {} units of the origin, not {} and {}",
+                MAX_QUANTIZED_POSITION, self.bounds.min, self.bounds.max
+            ));
+        }
+        if let Some(wall) = self.walls.iter().find(|wall| !in_range(wall)) {
+            return Err(format!(
+                "walls must be within {} units of the origin, not {} and {}",
+                MAX_QUANTIZED_POSITION, wall.min, wall.max
+            ));
+        }
+        if let Some(decoration) = self
+            .decorations
+            .iter()
+            .find(|decoration| !is_valid(decoration.min, decoration.max))
+        {
+            return Err(format!(
+                "decorations must have a min smaller than their max, not {} and {}",
+                decoration.min, decoration.max
+            ));
+        }
+        if self.spawn_points.is_empty() {
+            return Err("there must be at least one spawn point".to_string());
+        }
+
+        // Players spawning in a wall would be pushed out on their first step,
+        // possibly out of the map
+        for spawn_point in &self.spawn_points {
+            if !self.bounds.contains(*spawn_point, -player_radius) {
+                return Err(format!(
+                    "spawn point {} is too close to the edge of the map",
+                    spawn_point
+                ));
+            }
+            if self.overlaps_wall(*spawn_point, player_radius) {
+                return Err(format!("spawn point {} is inside a wall", spawn_point));
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the first surface that a circle moving from `start` to `end` hits,
+    /// skipping the wall at index `ignore`. Walls that the circle starts inside
+    /// of are not hit.
+    pub fn raycast(
+        &self,
+        start: Vec2,
+        end: Vec2,
+        radius: f32,
+        ignore: Option<usize>,
+    ) -> Option<RayHit> {
+        let wall_hit = self
+            .walls
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| Some(*index) != ignore)
+            .filter_map(|(index, wall)| {
+                let (fraction, normal) = wall.raycast(start, end, radius)?;
+                Some(RayHit {
+                    fraction,
+                    normal,
+                    wall: Some(index),
+                })
+            })
+            .min_by(|a, b| a.fraction.total_cmp(&b.fraction));
+
+        // The bounds are hit from the inside, when the ray leaves them
+        let delta = end - start;
+        let mut bounds_hit: Option<RayHit> = None;
+        for axis in 0..2 {
+            let edge = if delta[axis] > 0.0 {
+                self.bounds.max[axis] - radius
+            } else if delta[axis] < 0.0 {
+                self.bounds.min[axis] + radius
+            } else {
+                continue;
+            };
+            let fraction = (edge - start[axis]) / delta[axis];
+            if fraction <= RAY_EPSILON || fraction > 1.0 {
+                continue;
+            }
+            if bounds_hit.is_none_or(|hit| fraction < hit.fraction) {
+                let mut normal = Vec2::ZERO;
+                normal[axis] = -delta[axis].signum();
+                bounds_hit = Some(RayHit {
+                    fraction,
+                    normal,
+                    wall: None,
+                });
+            }
+        }
+
+        match (wall_hit, bounds_hit) {
+            (Some(wall_hit), Some(bounds_hit)) if bounds_hit.fraction < wall_hit.fraction => {
+                Some(bounds_hit)
+            }
+            (Some(wall_hit), _) => Some(wall_hit),
+            (None, bounds_hit) => bounds_hit,
+        }
+    }
+
+    /// Whether a circle at `position` overlaps any wall.
+    pub fn overlaps_wall(&self, position: Vec2, radius: f32) -> bool {
+        self.walls
+            .iter()
+            .any(|wall| wall.contains(position, radius))
+    }
+
+    /// Moves a circle out of the walls and back inside the bounds, removing
+    /// the part of its velocity that points into them.
+    pub fn resolve_collisions(&self, position: &mut Vec2, velocity: &mut Vec2, radius: f32) {
+        for _ in 0..COLLISION_PASSES {
+            for wall in &self.walls {
+                if let Some(normal) = wall.push_out(position, radius) {
+                    *velocity -= normal * velocity.dot(normal).min(0.0);
+                }
+            }
+
+            let min = self.bounds.min + radius;
+            let max = self.bounds.max - radius;
+            let clamped = position.clamp(min.min(max), max.max(min));
+            if clamped.x != position.x {
+                velocity.x = 0.0;
+            }
+            if clamped.y != position.y {
+                velocity.y = 0.0;
+            }
+            *position = clamped;
+        }
+    }
+}
+
+/// A 20 by 20 map with a single wall right of the center, for tests.
+#[cfg(test)]
+pub(crate) fn test_map() -> Map {
+    Map {
+        name: "Test".to_string(),
+        bounds: Wall {
+            min: Vec2::splat(-10.0),
+            max: Vec2::splat(10.0),
+        },
+        walls: vec![Wall {
+            min: Vec2::new(4.0, -2.0),
+            max: Vec2::new(5.0, 2.0),
+        }],
+        decorations: Vec::new(),
+        spawn_points: vec![Vec2::ZERO],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_map_is_valid() {
+        let map = Map::default();
+        assert_eq!(map.name, "Arena");
+        assert!(!map.walls.is_empty());
+    }
+
+    #[test]
+    fn validate_rejects_maps_beyond_quantization_range() {
+        let mut map = test_map();
+        map.bounds.max.x = MAX_QUANTIZED_POSITION + 1.0;
+        assert!(map.validate(0.5).is_err());
+
+        let mut map = test_map();
+        map.walls[0].min.y = -MAX_QUANTIZED_POSITION - 1.0;
+        assert!(map.validate(0.5).is_err());
+    }
+
+    #[test]
+    fn raycast_hits_wall() {
+        let hit = test_map()
+            .raycast(Vec2::ZERO, Vec2::new(10.0, 0.0), 0.0, None)
+            .unwrap();
+        assert!((hit.fraction - 0.4).abs() < 1e-5);
+        assert_eq!(hit.normal, Vec2::NEG_X);
+        assert_eq!(hit.wall, Some(0));
+    }
+
+    #[test]
+    fn raycast_accounts_for_radius() {
+        let hit = test_map()
+            .raycast(Vec2::ZERO, Vec2::new(10.0, 0.0), 1.0, None)
+            .unwrap();
+        assert!((hit.fraction - 0.3).abs() < 1e-5);
+    }
+
+    #[test]
+    fn raycast_skips_ignored_wall() {
+        let hit = test_map()
+            .raycast(Vec2::ZERO, Vec2::new(20.0, 0.0), 0.0, Some(0))
+            .unwrap();
+        assert!((hit.fraction - 0.5).abs() < 1e-5);
+        assert_eq!(hit.wall, None);
+    }
+
+    #[test]
+    fn raycast_hits_map_edge() {
+        let hit = test_map()
+            .raycast(Vec2::new(0.0, 5.0), Vec2::new(0.0, 25.0), 1.0, None)
+            .unwrap();
+        assert!((hit.fraction - 0.2).abs() < 1e-5);
+        assert_eq!(hit.normal, Vec2::NEG_Y);
+        assert_eq!(hit.wall, None);
+    }
+
+    #[test]
+    fn raycast_misses_when_nothing_is_in_the_way() {
+        let map = test_map();
+        assert!(map
+            .raycast(Vec2::ZERO, Vec2::new(-5.0, 5.0), 0.0, None)
+            .is_none());
+        // Rays starting on the edge of the map can leave it
+        assert!(map
+            .raycast(Vec2::new(10.0, 5.0), Vec2::new(20.0, 5.0), 0.0, None)
+            .is_none());
+    }
+
+    #[test]
+    fn raycast_ignores_wall_it_starts_inside() {
+        let hit = test_map().raycast(Vec2::new(4.5, 0.0), Vec2::new(4.5, 20.0), 0.0, None);
+        assert_eq!(hit.map(|hit| hit.wall), Some(None));
+    }
+
+    #[test]
+    fn push_out_moves_overlapping_circle_to_the_surface() {
+        let wall = test_map().walls[0];
+
+        let mut position = Vec2::new(3.8, 0.0);
+        assert_eq!(wall.push_out(&mut position, 0.5), Some(Vec2::NEG_X));
+        assert!(position.abs_diff_eq(Vec2::new(3.5, 0.0), 1e-5));
+
+        // From the corner, the circle is pushed away diagonally
+        let mut position = Vec2::new(5.1, 2.1);
+        let normal = wall.push_out(&mut position, 0.5).unwrap();
+        assert!(normal.abs_diff_eq(Vec2::ONE.normalize(), 1e-5));
+        assert!(((position - wall.max).length() - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn push_out_moves_contained_circle_through_nearest_side() {
+        let wall = test_map().walls[0];
+        let mut position = Vec2::new(4.8, 1.0);
+        assert_eq!(wall.push_out(&mut position, 0.5), Some(Vec2::X));
+        assert!(position.abs_diff_eq(Vec2::new(5.5, 1.0), 1e-5));
+    }
+
+    #[test]
+    fn push_out_leaves_distant_circle_alone() {
+        let wall = test_map().walls[0];
+        let mut position = Vec2::new(3.0, 0.0);
+        assert_eq!(wall.push_out(&mut position, 0.5), None);
+        assert_eq!(position, Vec2::new(3.0, 0.0));
+    }
+}
@@ -1,28 +1,169 @@
+use std::fmt;
+
 use bevy::prelude::*;
-use bevy::utils::HashMap;
 use renet::ClientId;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    player_controller::PlayerController,
-    remote_state::{RemoteBulletState, RemotePlayerState},
+    game_match::MatchStatus, map::Map, player_controller::PlayerInput,
+    replication::ReplicationMessage, rules::GameRules, snapshot::PlayerSnapshot,
 };
 
 /// This ID is assigned by the server and is included in entity synchronization
 /// messages as a persistent handle. It can be attached to entities or mapped to
 /// entities in a resource.
-#[derive(Deref, DerefMut, Component, Debug, Serialize, Deserialize, PartialEq, Eq, Default, Hash)]
+#[derive(
+    Deref,
+    DerefMut,
+    Component,
+    Clone,
+    Copy,
+    Debug,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    Default,
+    Hash,
+)]
 pub struct NetworkId(pub u32);
 
+/// Version of the messages exchanged between clients and servers. Must be
+/// increased whenever the format of any message changes, except for the
+/// handshake messages, which must stay readable by every version.
+pub const PROTOCOL_VERSION: u32 = 12;
+
+/// Optional protocol behaviour that the client and server agree on during the
+/// handshake.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+
+    /// Features this build supports. Optional additions to the protocol should
+    /// get a flag here instead of a version bump.
+    pub const SUPPORTED: Self = Self::NONE;
+
+    /// Features the server refuses to play without.
+    pub const REQUIRED: Self = Self::NONE;
+
+    pub fn contains(self, features: Self) -> bool {
+        self.0 & features.0 == features.0
+    }
+
+    pub fn intersection(self, features: Self) -> Self {
+        Self(self.0 & features.0)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HandshakeResponse {
+    /// The client may play, using the given features.
+    Accepted {
+        features: Features,
+    },
+    Rejected(RejectReason),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RejectReason {
+    VersionMismatch { server_version: u32 },
+    MissingFeatures(Features),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VersionMismatch { server_version } => write!(
+                f,
+                "server runs protocol version {}, but this client runs version {}",
+                server_version, PROTOCOL_VERSION
+            ),
+            Self::MissingFeatures(features) => {
+                write!(
+                    f,
+                    "client lacks features required by the server ({:?})",
+                    features
+                )
+            }
+        }
+    }
+}
+
+/// State of a player that is not part of snapshots.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerStatus {
+    pub name: String,
+    pub health: f32,
+    pub dead: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
-    PlayerConnected { client_id: ClientId },
-    PlayerDisconnected { client_id: ClientId },
-    Players(HashMap<ClientId, RemotePlayerState>),
-    Bullets(HashMap<NetworkId, RemoteBulletState>),
+    /// Answer to `ClientMessage::Hello`. It must stay the first variant so
+    /// that clients of any version can read it.
+    Handshake(HandshakeResponse),
+    PlayerConnected {
+        client_id: ClientId,
+        name: String,
+    },
+    PlayerDisconnected {
+        client_id: ClientId,
+    },
+    PlayerDamaged {
+        client_id: ClientId,
+        health: f32,
+    },
+    PlayerDied {
+        client_id: ClientId,
+        killer: Option<ClientId>,
+    },
+    PlayerRespawned {
+        client_id: ClientId,
+    },
+    Players(PlayerSnapshot),
+    Replication(Vec<ReplicationMessage>),
+    /// Sent to a client when it connects, listing the players that were
+    /// already connected.
+    ConnectedPlayers(Vec<(ClientId, PlayerStatus)>),
+    /// Sent when players become relevant to a client. Damage, deaths and
+    /// respawns are only sent to the clients that a player is relevant to, so
+    /// the client's last known status of these players may be out of date.
+    PlayersInRange(Vec<(ClientId, PlayerStatus)>),
+    /// Sent to a client once it has been accepted, describing how the server
+    /// runs the game.
+    ServerInfo {
+        tick_rate: f64,
+        rules: GameRules,
+    },
+    /// Sent to a client once it has been accepted, right after `ServerInfo`.
+    Map(Map),
+    /// Sent whenever the phase of the match or the scores change, and to
+    /// clients once they have been accepted, right after `Map`.
+    MatchStatus(MatchStatus),
+    /// Answer to `ClientMessage::Ping`, with the server's times in
+    /// milliseconds since epoch when the ping was received and when the pong
+    /// was sent.
+    Pong {
+        client_time: u64,
+        receive_time: u64,
+        server_time: u64,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-    Controller(PlayerController),
+    /// First message sent by a client after connecting. It must stay the
+    /// first variant so that servers of any version can read it.
+    Hello {
+        version: u32,
+        features: Features,
+    },
+    Input(PlayerInput),
+    /// Asks the server for its time, to synchronize clocks. `client_time` is
+    /// the client's time in milliseconds since epoch, and is sent back as is.
+    Ping {
+        client_time: u64,
+    },
 }
@@ -1 +1,5 @@
-use bevy::{ecs::system::SystemParam, prelude::*, sprite::Mesh2dHandle};
+use bevy::prelude::*;
+
+/// Name a player is shown with.
+#[derive(Component, Clone, Debug, Deref)]
+pub struct PlayerName(pub String);
@@ -1,7 +1,24 @@
+use std::f32::consts::{PI, TAU};
+
 use bevy::{prelude::*, window::PrimaryWindow};
 use serde::{Deserialize, Serialize};
 
-pub const PLAYER_SPEED: f32 = 15.0;
+use crate::{health::Dead, map::Map, rules::GameRules, tick::Tick};
+
+pub const PLAYER_RADIUS: f32 = 0.6;
+
+/// Keys that select the weapon at their index.
+const WEAPON_KEYS: [KeyCode; 9] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
 
 pub struct PlayerControllerPlugin {
     // If headless, player controllers will not be updated using local inputs.
@@ -11,30 +28,130 @@ pub struct PlayerControllerPlugin {
 
 impl Plugin for PlayerControllerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(FixedUpdate, apply_controls);
+        app.init_resource::<GameRules>()
+            .init_resource::<Map>()
+            .configure_sets(FixedUpdate, ControlSet::Read.before(ControlSet::Apply))
+            .add_systems(FixedUpdate, apply_controls.in_set(ControlSet::Apply));
 
         if !self.headless {
-            app.add_systems(FixedUpdate, read_controls.before(apply_controls));
+            app.add_systems(FixedUpdate, read_controls.in_set(ControlSet::Read));
+        }
+    }
+}
+
+/// Fixed update stages of player control. Anything that writes controller
+/// inputs should run in `Read` so that it is applied in the same tick.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ControlSet {
+    Read,
+    Apply,
+}
+
+/// Action buttons held by a player, packed as bit flags.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ActionButtons(u8);
+
+impl ActionButtons {
+    pub const FIRE: Self = Self(1 << 0);
+    pub const RELOAD: Self = Self(1 << 1);
+
+    /// Every button that is currently defined.
+    const ALL: Self = Self(Self::FIRE.0 | Self::RELOAD.0);
+
+    pub fn contains(self, buttons: Self) -> bool {
+        self.0 & buttons.0 == buttons.0
+    }
+
+    pub fn set(&mut self, buttons: Self, pressed: bool) {
+        if pressed {
+            self.0 |= buttons.0;
+        } else {
+            self.0 &= !buttons.0;
         }
     }
 }
 
-#[derive(Component, Clone, Default, Serialize, Deserialize, Debug)]
+/// Input sent by a client to the server. Only describes what the player is
+/// trying to do; the resulting motion is always computed by the server.
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct PlayerInput {
+    /// Increases by one for every input sent by a client.
+    pub sequence: u32,
+
+    pub move_direction: Vec2,
+
+    pub target_angle: f32,
+
+    pub buttons: ActionButtons,
+
+    /// Index of the weapon the player has selected.
+    pub weapon: u8,
+
+    /// Tick of the latest snapshot the client had received.
+    pub snapshot_tick: Tick,
+
+    /// How far behind `snapshot_tick` the client renders other players.
+    pub interpolation_delay_ms: u16,
+}
+
+#[derive(Component, Clone, Default, Debug)]
 pub struct PlayerController {
     /// Direction player is trying to move. Magnitude shall always be less than
     /// or equal to 1.
     pub move_direction: Vec2,
 
     /// Current player motion. May be replaced by a physics plugin later.
+    ///
+    /// Only ever written by `apply_controls`, so it is never taken from client
+    /// input.
     pub velocity: Vec2,
 
     /// Angle player is trying to face towards.
     pub target_angle: f32,
+
+    /// Action buttons the player is holding.
+    pub buttons: ActionButtons,
+
+    /// Index of the weapon the player has selected. Weapons that don't exist
+    /// are ignored by the server.
+    pub weapon: u8,
+}
+
+impl PlayerController {
+    /// Creates an input message describing what this controller is trying to do.
+    pub fn to_input(&self, sequence: u32) -> PlayerInput {
+        PlayerInput {
+            sequence,
+            move_direction: self.move_direction,
+            target_angle: self.target_angle,
+            buttons: self.buttons,
+            weapon: self.weapon,
+            ..default()
+        }
+    }
+
+    /// Updates this controller from untrusted input, clamping any values that
+    /// could not have come from a legitimate client.
+    pub fn apply_input(&mut self, input: &PlayerInput) {
+        self.move_direction = if input.move_direction.is_finite() {
+            input.move_direction.clamp_length_max(1.0)
+        } else {
+            Vec2::ZERO
+        };
+
+        if input.target_angle.is_finite() {
+            self.target_angle = (input.target_angle + PI).rem_euclid(TAU) - PI;
+        }
+
+        self.buttons = ActionButtons(input.buttons.0 & ActionButtons::ALL.0);
+        self.weapon = input.weapon;
+    }
 }
 
 fn read_controls(
     mut controllers: Query<(&mut PlayerController, &GlobalTransform)>,
     keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
     windows: Query<&Window, With<PrimaryWindow>>,
     camera: Query<(&Camera, &GlobalTransform)>,
 ) {
@@ -60,6 +177,19 @@ fn read_controls(
         .as_vec2()
         .normalize_or_zero();
 
+        controller.buttons.set(
+            ActionButtons::FIRE,
+            mouse_buttons.pressed(MouseButton::Left),
+        );
+        controller
+            .buttons
+            .set(ActionButtons::RELOAD, keys.pressed(KeyCode::R));
+
+        // Number keys select weapons
+        if let Some(weapon) = WEAPON_KEYS.iter().position(|key| keys.pressed(*key)) {
+            controller.weapon = weapon as u8;
+        }
+
         if let Some(hovered_position) = hovered_position {
             let diff = hovered_position - controller_transform.translation().xy();
             controller.target_angle = diff.y.atan2(diff.x);
@@ -68,23 +198,51 @@ fn read_controls(
 }
 
 fn apply_controls(
-    mut players: Query<(&mut PlayerController, &mut Transform)>,
+    mut players: Query<(&mut PlayerController, &mut Transform, Option<&Dead>)>,
+    rules: Res<GameRules>,
+    map: Res<Map>,
     time: Res<Time<Fixed>>,
 ) {
-    for (mut controller, mut transform) in players.iter_mut() {
-        // Update velocity
-        controller.velocity = controller.velocity.lerp(
-            controller.move_direction * PLAYER_SPEED,
-            time.delta_seconds() * 5.0,
-        );
-
-        // Update position
-        transform.translation += (controller.velocity * time.delta_seconds()).extend(0.0);
+    for (mut controller, mut transform, dead) in players.iter_mut() {
+        if dead.is_some() {
+            controller.velocity = Vec2::ZERO;
+            continue;
+        }
 
-        // Update angle
-        transform.rotation = transform.rotation.lerp(
-            Quat::from_rotation_z(controller.target_angle),
-            time.delta_seconds() * 5.0,
+        simulate(
+            &mut controller,
+            &mut transform,
+            &rules,
+            &map,
+            time.delta_seconds(),
         );
     }
 }
+
+/// Advances a player by one step of `delta_seconds`. This is shared by the
+/// server simulation and client-side prediction, so both must produce the same
+/// result for the same inputs.
+pub fn simulate(
+    controller: &mut PlayerController,
+    transform: &mut Transform,
+    rules: &GameRules,
+    map: &Map,
+    delta_seconds: f32,
+) {
+    // Update velocity
+    controller.velocity = controller.velocity.lerp(
+        controller.move_direction * rules.player_speed,
+        delta_seconds * 5.0,
+    );
+
+    // Update position, sliding along any walls in the way
+    let mut position = transform.translation.xy() + controller.velocity * delta_seconds;
+    map.resolve_collisions(&mut position, &mut controller.velocity, PLAYER_RADIUS);
+    transform.translation = position.extend(transform.translation.z);
+
+    // Update angle
+    transform.rotation = transform.rotation.lerp(
+        Quat::from_rotation_z(controller.target_angle),
+        delta_seconds * 5.0,
+    );
+}
@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{
+    health::Dead,
+    map::Map,
+    player_controller::{simulate, PlayerController, PlayerInput},
+    remote_state::RemotePlayerState,
+    rules::GameRules,
+    tick::Tick,
+};
+
+/// Maximum number of inputs kept while waiting for the server to acknowledge
+/// them. Older inputs are dropped, which only costs accuracy when replaying.
+const MAX_PENDING_INPUTS: usize = 128;
+
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<LocalPlayerSnapshot>()
+            .insert_resource(LastReconciledTick::default())
+            .add_systems(Update, reconcile);
+    }
+}
+
+/// Marks the player controlled by this client.
+#[derive(Component, Default)]
+pub struct LocalPlayer;
+
+/// Inputs that have been applied locally but not yet acknowledged by the
+/// server.
+#[derive(Component, Default)]
+pub struct PredictionHistory {
+    pending: VecDeque<PlayerInput>,
+}
+
+impl PredictionHistory {
+    pub fn push(&mut self, input: PlayerInput) {
+        if self.pending.len() >= MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(input);
+    }
+}
+
+/// Authoritative state of the local player received from the server.
+#[derive(Event)]
+pub struct LocalPlayerSnapshot {
+    pub tick: Tick,
+    pub state: RemotePlayerState,
+
+    /// Sequence number of the last input the server applied.
+    pub input_ack: u32,
+}
+
+/// Tick of the snapshot the local player was last reconciled with. Snapshots
+/// are unreliable, so older ones arriving late must be ignored.
+#[derive(Resource, Default, Deref, DerefMut)]
+struct LastReconciledTick(Option<Tick>);
+
+fn reconcile(
+    mut snapshots: EventReader<LocalPlayerSnapshot>,
+    mut players: Query<
+        (
+            &mut PlayerController,
+            &mut Transform,
+            &mut PredictionHistory,
+            Option<&Dead>,
+        ),
+        With<LocalPlayer>,
+    >,
+    rules: Res<GameRules>,
+    map: Res<Map>,
+    time: Res<Time<Fixed>>,
+    mut last_tick: ResMut<LastReconciledTick>,
+) {
+    // Only the most recent snapshot matters, as it supersedes the others.
+    let Some(snapshot) = snapshots.read().max_by_key(|snapshot| snapshot.tick) else {
+        return;
+    };
+    if last_tick.is_some_and(|tick| tick >= snapshot.tick) {
+        return;
+    }
+    **last_tick = Some(snapshot.tick);
+    let input_ack = snapshot.input_ack;
+    let snapshot = &snapshot.state;
+
+    for (mut controller, mut transform, mut history, dead) in players.iter_mut() {
+        history.pending.retain(|input| input.sequence > input_ack);
+
+        // Rewind to the server's state
+        transform.translation = snapshot.position.extend(transform.translation.z);
+        transform.rotation = Quat::from_rotation_z(snapshot.angle);
+        controller.velocity = snapshot.velocity;
+
+        // Dead players do not move, so there is nothing to replay
+        if dead.is_some() {
+            continue;
+        }
+
+        // Replay inputs the server has not processed yet
+        for input in history.pending.iter() {
+            controller.apply_input(input);
+            simulate(
+                &mut controller,
+                &mut transform,
+                &rules,
+                &map,
+                time.timestep().as_secs_f32(),
+            );
+        }
+    }
+}
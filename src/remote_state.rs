@@ -1,15 +1,176 @@
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{collections::VecDeque, time::Duration};
 
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::rendering::interpolate_transform;
+use crate::{
+    bullet::BULLET_RADIUS,
+    clock::ServerClock,
+    map::Map,
+    tick::{Tick, TickRate},
+};
 
-pub struct RemotePlayerControllerPlugin;
+/// How long bullets fly before disappearing, in milliseconds.
+pub const BULLET_LIFETIME_MS: u64 = 2000;
+
+/// Most wall hits a bullet path is traced through. Bullets that would hit more
+/// walls than this stop at the last one.
+const MAX_PATH_SEGMENTS: usize = 16;
+
+/// Maximum number of snapshots buffered per remote entity.
+const MAX_BUFFERED_SNAPSHOTS: usize = 32;
+
+/// If a received snapshot is further than this from the estimated server tick,
+/// the estimate is reset instead of being gradually corrected.
+const CLOCK_RESET_THRESHOLD: Duration = Duration::from_millis(500);
+
+/// Fraction of the error between the estimated and received server tick that
+/// is corrected per snapshot.
+const CLOCK_CORRECTION: f64 = 0.1;
+
+pub struct RemotePlayerControllerPlugin {
+    pub interpolation: InterpolationSettings,
+}
 
 impl Plugin for RemotePlayerControllerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, update_players);
+        app.insert_resource(self.interpolation)
+            .init_resource::<TickRate>()
+            .init_resource::<ServerClock>()
+            .insert_resource(SnapshotClock::default())
+            .add_systems(Update, (advance_snapshot_clock, update_players).chain())
+            .add_systems(Update, update_bullets);
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct InterpolationSettings {
+    /// How far behind the latest received snapshots remote entities are
+    /// rendered. Larger delays hide more network jitter and packet loss.
+    pub delay: Duration,
+
+    /// How far past their latest snapshot remote entities may be extrapolated
+    /// when no newer snapshot has arrived.
+    pub max_extrapolation: Duration,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(100),
+            max_extrapolation: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Client estimate of the server tick that snapshots are currently arriving
+/// for.
+#[derive(Resource, Default, Debug)]
+pub struct SnapshotClock {
+    tick: Option<f64>,
+
+    /// Tick of the newest snapshot received so far.
+    latest: Tick,
+}
+
+impl SnapshotClock {
+    /// Corrects the estimate using the tick of a newly received snapshot.
+    pub fn observe(&mut self, tick: Tick, tick_rate: TickRate) {
+        self.latest = self.latest.max(tick);
+
+        let received = *tick as f64;
+        let reset_threshold = tick_rate.ticks(CLOCK_RESET_THRESHOLD);
+        self.tick = match self.tick {
+            Some(estimate) if (received - estimate).abs() < reset_threshold => {
+                Some(estimate + (received - estimate) * CLOCK_CORRECTION)
+            }
+            _ => Some(received),
+        };
+    }
+
+    pub fn latest_tick(&self) -> Tick {
+        self.latest
+    }
+
+    /// Tick that remote entities should currently be displayed at.
+    pub fn render_tick(&self, delay: Duration, tick_rate: TickRate) -> Option<f64> {
+        self.tick.map(|tick| tick - tick_rate.ticks(delay))
+    }
+}
+
+/// Snapshots of a remote entity waiting to be displayed, ordered by tick.
+#[derive(Component, Default, Debug)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<(Tick, RemotePlayerState)>,
+}
+
+impl SnapshotBuffer {
+    pub fn insert(&mut self, tick: Tick, state: RemotePlayerState) {
+        // Snapshots are unreliable, so they may arrive out of order or twice
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|(buffered_tick, _)| *buffered_tick <= tick)
+            .map_or(0, |index| index + 1);
+        if index > 0 && self.snapshots[index - 1].0 == tick {
+            return;
+        }
+        self.snapshots.insert(index, (tick, state));
+
+        if self.snapshots.len() > MAX_BUFFERED_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Computes the state at `tick`, interpolating between the surrounding
+    /// snapshots. Past the latest snapshot, the state is extrapolated by at most
+    /// `max_extrapolation` ticks.
+    fn sample(
+        &mut self,
+        tick: f64,
+        max_extrapolation: f64,
+        tick_rate: TickRate,
+    ) -> Option<RemotePlayerState> {
+        // Drop snapshots that are no longer needed to interpolate
+        while self.snapshots.len() > 2 && (*self.snapshots[1].0 as f64) <= tick {
+            self.snapshots.pop_front();
+        }
+
+        let (first_tick, first) = self.snapshots.front()?;
+        if tick <= **first_tick as f64 {
+            return Some(first.clone());
+        }
+
+        match self.snapshots.get(1) {
+            Some((second_tick, second)) if tick <= **second_tick as f64 => {
+                let from = **first_tick as f64;
+                let to = **second_tick as f64;
+                let factor = ((tick - from) / (to - from)) as f32;
+
+                Some(RemotePlayerState {
+                    position: first.position.lerp(second.position, factor),
+                    angle: Quat::from_rotation_z(first.angle)
+                        .slerp(Quat::from_rotation_z(second.angle), factor)
+                        .to_euler(EulerRot::XYZ)
+                        .2,
+                    velocity: first.velocity.lerp(second.velocity, factor),
+                })
+            }
+            _ => {
+                let (latest_tick, latest) = self.snapshots.back()?;
+                let ticks_ahead = (tick - **latest_tick as f64).min(max_extrapolation);
+                let seconds_ahead = tick_rate.seconds(ticks_ahead) as f32;
+
+                Some(RemotePlayerState {
+                    position: latest.position + latest.velocity * seconds_ahead,
+                    ..latest.clone()
+                })
+            }
+        }
     }
 }
 
@@ -17,6 +178,7 @@ impl Plugin for RemotePlayerControllerPlugin {
 pub struct RemotePlayerState {
     pub position: Vec2,
     pub angle: f32,
+    pub velocity: Vec2,
 }
 
 #[derive(Component, Default, Serialize, Deserialize, Debug, Clone)]
@@ -27,28 +189,363 @@ pub struct RemoteBulletState {
     /// Direction the bullet was shot towards.
     pub angle: f32,
 
-    /// Milliseconds since epoch that the bullet was shot.
+    /// Server time that the bullet was shot at, in milliseconds since epoch.
     pub spawn_time: u64,
 
     pub speed: f32,
+
+    pub wall_impact: WallImpact,
+}
+
+/// What a bullet does when it hits a wall or the edge of the map.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WallImpact {
+    #[default]
+    Stop,
+    /// Reflects off up to `max_bounces` surfaces, then stops at the next.
+    Bounce { max_bounces: u8 },
+    /// Passes through up to `max_walls` walls, then stops at the next. The
+    /// edge of the map always stops bullets.
+    Penetrate { max_walls: u8 },
+}
+
+impl RemoteBulletState {
+    /// Traces the bullet through `map`. The path only depends on the bullet
+    /// and the map, so the server and clients agree on where the bullet is as
+    /// long as their clocks are synchronized.
+    pub fn path(&self, map: &Map) -> BulletPath {
+        let lifetime = BULLET_LIFETIME_MS as f32 / 1000.0;
+        let mut path = BulletPath {
+            spawn_time: self.spawn_time,
+            segments: Vec::new(),
+            end: 0.0,
+        };
+
+        // Bullets shot from inside a wall are stopped right away
+        if map.overlaps_wall(self.origin, BULLET_RADIUS) {
+            return path;
+        }
+
+        let mut start = self.origin;
+        let mut direction = Vec2::from_angle(self.angle);
+        let mut time = 0.0;
+        let mut impacts_left = match self.wall_impact {
+            WallImpact::Stop => 0,
+            WallImpact::Bounce { max_bounces } => max_bounces,
+            WallImpact::Penetrate { max_walls } => max_walls,
+        };
+        let mut ignore = None;
+
+        for _ in 0..MAX_PATH_SEGMENTS {
+            path.segments.push(PathSegment {
+                start_time: time,
+                start,
+                velocity: direction * self.speed,
+            });
+
+            let remaining = lifetime - time;
+            let end = start + direction * self.speed * remaining;
+            let Some(hit) = map.raycast(start, end, BULLET_RADIUS, ignore) else {
+                path.end = lifetime;
+                return path;
+            };
+
+            time += remaining * hit.fraction;
+            start = start.lerp(end, hit.fraction);
+            path.end = time;
+
+            let passes = match self.wall_impact {
+                WallImpact::Stop => false,
+                WallImpact::Bounce { .. } => impacts_left > 0,
+                WallImpact::Penetrate { .. } => impacts_left > 0 && hit.wall.is_some(),
+            };
+            if !passes {
+                return path;
+            }
+            impacts_left -= 1;
+
+            // A box can't be hit twice in a row by a straight line, or by its
+            // own reflection
+            ignore = hit.wall;
+            if let WallImpact::Bounce { .. } = self.wall_impact {
+                direction -= 2.0 * direction.dot(hit.normal) * hit.normal;
+            }
+        }
+        path
+    }
 }
 
-fn update_players(time: Res<Time>, mut players: Query<(&mut RemotePlayerState, &mut Transform)>) {
-    for (state, mut transform) in players.iter_mut() {
-        let mut new_transform = transform.clone();
-        new_transform.translation = state.position.extend(transform.translation.z);
-        new_transform.rotation = Quat::from_rotation_z(state.angle);
+#[derive(Clone, Copy, Debug)]
+struct PathSegment {
+    /// Seconds after the bullet was shot that it starts this segment.
+    start_time: f32,
+    start: Vec2,
+    velocity: Vec2,
+}
 
-        interpolate_transform(&mut transform, &new_transform, 1.0);
+impl PathSegment {
+    fn position(&self, time: f32) -> Vec2 {
+        self.start + self.velocity * (time - self.start_time)
     }
 }
 
-// fn update_bullets(time: Res<Time>, mut bullets: Query<(&mut RemoteBulletState, &mut Transform)>) {
-//     for (state, mut transform) in bullets.iter_mut() {
-//         let age =
-//             SystemTime::now().duration_since(UNIX_EPOCH + Duration::from_millis(state.spawn_time));
-//         let distance_travelled = age * state.speed;
+/// Trajectory of a bullet through the map, as straight segments between the
+/// surfaces it bounces off or passes through.
+#[derive(Component, Clone, Debug)]
+pub struct BulletPath {
+    spawn_time: u64,
+    segments: Vec<PathSegment>,
+
+    /// Seconds after the bullet was shot that it stops.
+    end: f32,
+}
+
+impl BulletPath {
+    /// Position and direction of the bullet at server time `time`, in
+    /// milliseconds since epoch, or `None` once it has stopped.
+    pub fn sample(&self, time: u64) -> Option<(Vec2, Vec2)> {
+        let age = time.saturating_sub(self.spawn_time) as f32 / 1000.0;
+        if age >= self.end {
+            return None;
+        }
 
-//         let mut new_transform = transform.clone();
-//     }
-// }
\ No newline at end of file
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| segment.start_time <= age)?;
+        Some((segment.position(age), segment.velocity.normalize_or_zero()))
+    }
+
+    /// Where the bullet stops, or `None` if it never moved.
+    pub fn end_position(&self) -> Option<Vec2> {
+        let segment = self.segments.last()?;
+        Some(segment.position(self.end))
+    }
+
+    /// Start and end of each straight piece the bullet travelled between
+    /// server times `from` and `to`, in milliseconds since epoch. Pieces are
+    /// split where the bullet bounces, so sweeping them follows its real
+    /// route rather than cutting corners.
+    pub fn segments_between(&self, from: u64, to: u64) -> impl Iterator<Item = (Vec2, Vec2)> + '_ {
+        let age = |time: u64| (time.saturating_sub(self.spawn_time) as f32 / 1000.0).min(self.end);
+        let (from, to) = (age(from), age(to));
+
+        self.segments
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, segment)| {
+                let segment_end = self
+                    .segments
+                    .get(index + 1)
+                    .map_or(self.end, |next| next.start_time);
+                let start = from.max(segment.start_time);
+                let end = to.min(segment_end);
+                (start < end).then(|| (segment.position(start), segment.position(end)))
+            })
+    }
+}
+
+fn advance_snapshot_clock(
+    time: Res<Time>,
+    tick_rate: Res<TickRate>,
+    mut clock: ResMut<SnapshotClock>,
+) {
+    if let Some(tick) = clock.tick.as_mut() {
+        *tick += tick_rate.ticks(time.delta());
+    }
+}
+
+fn update_players(
+    clock: Res<SnapshotClock>,
+    settings: Res<InterpolationSettings>,
+    tick_rate: Res<TickRate>,
+    mut players: Query<(&mut SnapshotBuffer, &mut Transform)>,
+) {
+    let Some(render_tick) = clock.render_tick(settings.delay, *tick_rate) else {
+        return;
+    };
+    let max_extrapolation = tick_rate.ticks(settings.max_extrapolation);
+
+    for (mut buffer, mut transform) in players.iter_mut() {
+        let Some(state) = buffer.sample(render_tick, max_extrapolation, *tick_rate) else {
+            continue;
+        };
+
+        transform.translation = state.position.extend(transform.translation.z);
+        transform.rotation = Quat::from_rotation_z(state.angle);
+    }
+}
+
+fn update_bullets(
+    mut commands: Commands,
+    server_clock: Res<ServerClock>,
+    mut bullets: Query<(Entity, &BulletPath, &mut Transform)>,
+) {
+    let now = server_clock.now();
+
+    for (entity, path, mut transform) in bullets.iter_mut() {
+        // The server announces the despawn as well, but the bullet is removed
+        // right away so that it doesn't linger while that message is in
+        // flight, or fly through the wall it hit
+        let Some((position, direction)) = path.sample(now) else {
+            commands.entity(entity).despawn();
+            continue;
+        };
+
+        transform.translation = position.extend(transform.translation.z);
+        transform.rotation = Quat::from_rotation_z(direction.y.atan2(direction.x));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::map::{test_map, Wall};
+
+    const SPAWN_TIME: u64 = 1_000_000;
+
+    fn bullet(origin: Vec2, angle: f32, wall_impact: WallImpact) -> RemoteBulletState {
+        RemoteBulletState {
+            origin,
+            angle,
+            spawn_time: SPAWN_TIME,
+            speed: 10.0,
+            wall_impact,
+        }
+    }
+
+    fn assert_near(actual: Vec2, expected: Vec2) {
+        assert!(
+            actual.abs_diff_eq(expected, 1e-3),
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn stopping_bullet_stops_at_wall() {
+        let path = bullet(Vec2::ZERO, 0.0, WallImpact::Stop).path(&test_map());
+
+        let (position, direction) = path.sample(SPAWN_TIME + 200).unwrap();
+        assert_near(position, Vec2::new(2.0, 0.0));
+        assert_near(direction, Vec2::X);
+
+        let wall_x = 4.0 - BULLET_RADIUS;
+        assert_near(path.end_position().unwrap(), Vec2::new(wall_x, 0.0));
+        assert!(path.sample(SPAWN_TIME + 400).is_none());
+    }
+
+    #[test]
+    fn stopping_bullet_stops_at_map_edge() {
+        let path = bullet(Vec2::ZERO, PI, WallImpact::Stop).path(&test_map());
+
+        let edge_x = -10.0 + BULLET_RADIUS;
+        assert_near(path.end_position().unwrap(), Vec2::new(edge_x, 0.0));
+        assert!(path.sample(SPAWN_TIME + 980).is_some());
+        assert!(path.sample(SPAWN_TIME + 1000).is_none());
+    }
+
+    #[test]
+    fn bouncing_bullet_reflects_off_wall() {
+        let path = bullet(Vec2::ZERO, 0.0, WallImpact::Bounce { max_bounces: 1 }).path(&test_map());
+
+        // Back from the wall, 0.61 seconds after the bounce
+        let (position, direction) = path.sample(SPAWN_TIME + 1000).unwrap();
+        assert_near(position, Vec2::new(-2.2, 0.0));
+        assert_near(direction, Vec2::NEG_X);
+
+        // Out of bounces, so the edge of the map stops it
+        let edge_x = -10.0 + BULLET_RADIUS;
+        assert_near(path.end_position().unwrap(), Vec2::new(edge_x, 0.0));
+    }
+
+    #[test]
+    fn segments_between_follow_bounces() {
+        let path = bullet(Vec2::ZERO, 0.0, WallImpact::Bounce { max_bounces: 1 }).path(&test_map());
+        let wall_x = 4.0 - BULLET_RADIUS;
+
+        let segments: Vec<_> = path
+            .segments_between(SPAWN_TIME + 200, SPAWN_TIME + 600)
+            .collect();
+        assert_eq!(segments.len(), 2);
+        assert_near(segments[0].0, Vec2::new(2.0, 0.0));
+        assert_near(segments[0].1, Vec2::new(wall_x, 0.0));
+        assert_near(segments[1].0, Vec2::new(wall_x, 0.0));
+        assert_near(segments[1].1, Vec2::new(2.0 * wall_x - 6.0, 0.0));
+
+        // Nothing is travelled after the bullet stops
+        let end = SPAWN_TIME + BULLET_LIFETIME_MS;
+        assert_eq!(path.segments_between(end, end + 100).count(), 0);
+    }
+
+    #[test]
+    fn bouncing_bullet_reflects_off_map_edge() {
+        let path = bullet(Vec2::ZERO, PI, WallImpact::Bounce { max_bounces: 1 }).path(&test_map());
+
+        let (_, direction) = path.sample(SPAWN_TIME + 1500).unwrap();
+        assert_near(direction, Vec2::X);
+
+        // Its lifetime runs out before it reaches the wall
+        let edge_x = -10.0 + BULLET_RADIUS;
+        let travelled = 10.0 * (BULLET_LIFETIME_MS as f32 / 1000.0) - edge_x.abs();
+        assert_near(
+            path.end_position().unwrap(),
+            Vec2::new(edge_x + travelled, 0.0),
+        );
+    }
+
+    #[test]
+    fn penetrating_bullet_passes_through_wall() {
+        let path =
+            bullet(Vec2::ZERO, 0.0, WallImpact::Penetrate { max_walls: 1 }).path(&test_map());
+
+        let (position, direction) = path.sample(SPAWN_TIME + 600).unwrap();
+        assert_near(position, Vec2::new(6.0, 0.0));
+        assert_near(direction, Vec2::X);
+
+        // The edge of the map always stops bullets
+        let edge_x = 10.0 - BULLET_RADIUS;
+        assert_near(path.end_position().unwrap(), Vec2::new(edge_x, 0.0));
+    }
+
+    #[test]
+    fn penetrating_bullet_stops_at_map_edge() {
+        let path = bullet(Vec2::ZERO, PI, WallImpact::Penetrate { max_walls: 3 }).path(&test_map());
+
+        let edge_x = -10.0 + BULLET_RADIUS;
+        assert_near(path.end_position().unwrap(), Vec2::new(edge_x, 0.0));
+        assert!(path.sample(SPAWN_TIME + 1000).is_none());
+    }
+
+    #[test]
+    fn bullet_that_hits_nothing_flies_for_its_lifetime() {
+        let path = bullet(Vec2::new(0.0, 5.0), 0.0, WallImpact::Stop).path(&Map {
+            bounds: Wall {
+                min: Vec2::splat(-100.0),
+                max: Vec2::splat(100.0),
+            },
+            ..test_map()
+        });
+
+        assert_near(path.end_position().unwrap(), Vec2::new(20.0, 5.0));
+        assert!(path.sample(SPAWN_TIME + BULLET_LIFETIME_MS - 10).is_some());
+        assert!(path.sample(SPAWN_TIME + BULLET_LIFETIME_MS).is_none());
+    }
+
+    #[test]
+    fn bullet_fired_inside_wall_stops_immediately() {
+        for wall_impact in [
+            WallImpact::Stop,
+            WallImpact::Bounce { max_bounces: 3 },
+            WallImpact::Penetrate { max_walls: 3 },
+        ] {
+            let path = bullet(Vec2::new(4.5, 0.0), 0.0, wall_impact).path(&test_map());
+            assert!(path.sample(SPAWN_TIME).is_none());
+            assert!(path.end_position().is_none());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use bevy::{ecs::system::SystemParam, prelude::*, sprite::Mesh2dHandle};
+
+#[derive(Component, Deref, DerefMut)]
+pub struct Renderer {
+    pub bullet: Entity,
+}
+
+#[derive(Bundle)]
+pub struct Bundle {
+    entity: Renderer,
+    pub mesh: ColorMesh2dBundle,
+}
+
+#[derive(SystemParam)]
+pub struct Factory<'w> {
+    meshes: ResMut<'w, Assets<Mesh>>,
+    materials: ResMut<'w, Assets<ColorMaterial>>,
+}
+
+impl Factory<'_> {
+    pub fn build(&mut self, bullet: Entity, transform: Transform) -> Bundle {
+        Bundle {
+            entity: Renderer { bullet },
+            mesh: ColorMesh2dBundle {
+                mesh: Mesh2dHandle(
+                    self.meshes.add(
+                        shape::Quad {
+                            size: Vec2::new(0.6, 0.2),
+                            ..Default::default()
+                        }
+                        .into(),
+                    ),
+                ),
+                material: self.materials.add(ColorMaterial {
+                    color: Color::YELLOW,
+                    ..Default::default()
+                }),
+                transform: Transform {
+                    translation: transform.translation.xy().extend(2.0),
+                    ..transform
+                },
+                ..Default::default()
+            },
+        }
+    }
+}
+
+pub fn update(
+    mut commands: Commands,
+    bullets: Query<&Transform, Without<Renderer>>,
+    mut renderers: Query<(Entity, &Renderer, &mut Transform)>,
+) {
+    for (renderer_entity, bullet_entity, mut renderer_transform) in renderers.iter_mut() {
+        // Bullets despawn regularly, so their renderers are removed with them
+        let Ok(bullet_transform) = bullets.get(**bullet_entity) else {
+            commands.entity(renderer_entity).despawn();
+            continue;
+        };
+
+        renderer_transform.translation = bullet_transform
+            .translation
+            .xy()
+            .extend(renderer_transform.translation.z);
+        renderer_transform.rotation = bullet_transform.rotation;
+    }
+}
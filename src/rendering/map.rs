@@ -0,0 +1,97 @@
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+    sprite::Mesh2dHandle,
+};
+
+use crate::map::Map;
+
+const FLOOR_COLOR: Color = Color::rgb(0.11, 0.11, 0.12);
+const DECORATION_COLOR: Color = Color::rgb(0.16, 0.16, 0.18);
+const WALL_COLOR: Color = Color::rgb(0.38, 0.42, 0.48);
+
+/// Part of the rendered map. The whole map is drawn with one entity per layer,
+/// which are rebuilt whenever the map changes.
+#[derive(Component)]
+pub struct Renderer;
+
+pub fn update(
+    mut commands: Commands,
+    map: Res<Map>,
+    renderers: Query<Entity, With<Renderer>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    if !map.is_changed() {
+        return;
+    }
+
+    for entity in renderers.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    // Layers are drawn below players, which are at a depth of one
+    let layers = [
+        (FLOOR_COLOR, 0.0, vec![(map.bounds.min, map.bounds.max)]),
+        (
+            DECORATION_COLOR,
+            0.1,
+            map.decorations
+                .iter()
+                .map(|decoration| (decoration.min, decoration.max))
+                .collect(),
+        ),
+        (
+            WALL_COLOR,
+            0.5,
+            map.walls.iter().map(|wall| (wall.min, wall.max)).collect(),
+        ),
+    ];
+
+    for (color, depth, rects) in layers {
+        if rects.is_empty() {
+            continue;
+        }
+
+        commands.spawn((
+            Renderer,
+            ColorMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(rects_mesh(&rects))),
+                material: materials.add(ColorMaterial {
+                    color,
+                    ..Default::default()
+                }),
+                transform: Transform::from_translation(Vec3::new(0.0, 0.0, depth)),
+                ..Default::default()
+            },
+        ));
+    }
+}
+
+/// Builds a single mesh out of axis-aligned rectangles given by their min and
+/// max corners.
+fn rects_mesh(rects: &[(Vec2, Vec2)]) -> Mesh {
+    let mut positions = Vec::with_capacity(rects.len() * 4);
+    let mut indices = Vec::with_capacity(rects.len() * 6);
+
+    for (min, max) in rects {
+        let first = positions.len() as u32;
+        positions.extend([
+            [min.x, min.y, 0.0],
+            [max.x, min.y, 0.0],
+            [max.x, max.y, 0.0],
+            [min.x, max.y, 0.0],
+        ]);
+        indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+    }
+
+    let normals = vec![[0.0, 0.0, 1.0]; positions.len()];
+    let uvs = vec![[0.0, 0.0]; positions.len()];
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
@@ -1,5 +1,9 @@
+mod bullet;
+mod map;
+mod name;
 mod player;
 
+pub use bullet::{Bundle as BulletRendererBundle, Factory as BulletRendererBundleFactory};
 pub use player::{Bundle as PlayerRendererBundle, Factory as PlayerRendererBundleFactory};
 
 use bevy::prelude::*;
@@ -8,7 +12,15 @@ pub struct RendererPlugin;
 
 impl Plugin for RendererPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, player::update);
+        app.add_systems(
+            Update,
+            (
+                map::update,
+                player::update,
+                bullet::update,
+                (name::spawn, name::update).chain(),
+            ),
+        );
     }
 }
 
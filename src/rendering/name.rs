@@ -0,0 +1,95 @@
+use bevy::prelude::*;
+
+use crate::{game_match::Team, health::Dead, player::PlayerName};
+
+/// How far above the center of a player its name is shown.
+const LABEL_OFFSET: Vec2 = Vec2::new(0.0, 1.4);
+
+/// Font size the label is rendered at, and the scale that brings it down to
+/// world units.
+const FONT_SIZE: f32 = 24.0;
+const LABEL_SCALE: f32 = 0.025;
+
+#[derive(Component, Deref, DerefMut)]
+pub struct Renderer {
+    pub player: Entity,
+}
+
+/// Spawns a label for every player that was just given a name.
+pub fn spawn(
+    mut commands: Commands,
+    players: Query<(Entity, &PlayerName, &Transform), Added<PlayerName>>,
+) {
+    for (player, name, transform) in players.iter() {
+        commands.spawn((
+            Renderer { player },
+            Text2dBundle {
+                text: Text::from_section(
+                    name.as_str(),
+                    TextStyle {
+                        font_size: FONT_SIZE,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ),
+                transform: Transform::from_translation(label_position(transform))
+                    .with_scale(Vec3::splat(LABEL_SCALE)),
+                ..default()
+            },
+        ));
+    }
+}
+
+type NamedPlayers<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Transform,
+        Option<&'static Dead>,
+        Option<&'static Team>,
+    ),
+    (With<PlayerName>, Without<Renderer>),
+>;
+
+type Labels<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Renderer,
+        &'static mut Transform,
+        &'static mut Visibility,
+        &'static mut Text,
+    ),
+>;
+
+pub fn update(mut commands: Commands, players: NamedPlayers, mut renderers: Labels) {
+    for (renderer_entity, player_entity, mut renderer_transform, mut visibility, mut text) in
+        renderers.iter_mut()
+    {
+        let Ok((player_transform, dead, team)) = players.get(**player_entity) else {
+            commands.entity(renderer_entity).despawn();
+            continue;
+        };
+
+        // Labels stay upright instead of turning with the player
+        renderer_transform.translation = label_position(player_transform);
+
+        *visibility = if dead.is_some() {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+
+        // Players learn their team after they are named, so the color is kept
+        // up to date instead of being set on spawn
+        let color = team.map_or(Color::WHITE, |team| team.color());
+        if text.sections[0].style.color != color {
+            text.sections[0].style.color = color;
+        }
+    }
+}
+
+fn label_position(player_transform: &Transform) -> Vec3 {
+    (player_transform.translation.xy() + LABEL_OFFSET).extend(3.0)
+}
@@ -1,7 +1,11 @@
 use bevy::{ecs::system::SystemParam, prelude::*, sprite::Mesh2dHandle};
 use bevy_renet::renet::ClientId;
 
-use crate::{player_controller::PlayerController, remote_state::RemotePlayerState};
+use crate::{
+    health::{Dead, Health},
+    player_controller::PlayerController,
+    remote_state::RemotePlayerState,
+};
 
 use super::interpolate_transform;
 
@@ -47,19 +51,60 @@ impl Factory<'_> {
     }
 }
 
+type Players<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Transform,
+        Option<&'static Health>,
+        Option<&'static Dead>,
+    ),
+    Without<Renderer>,
+>;
+
+type Renderers<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Renderer,
+        &'static mut Transform,
+        &'static mut Visibility,
+        &'static Handle<ColorMaterial>,
+    ),
+>;
+
 pub fn update(
-    players: Query<&Transform, Without<Renderer>>,
-    mut renderers: Query<(&Renderer, &mut Transform)>,
+    mut commands: Commands,
+    players: Players,
+    mut renderers: Renderers,
+    mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
-    for (player_entity, mut renderer_transform) in renderers.iter_mut() {
-        let Ok(player_transform) = players.get(**player_entity) else {
-            warn!(
-                "Player renderer references a player entity ({:?}) that does not exist",
-                **player_entity
-            );
+    for (renderer_entity, player_entity, mut renderer_transform, mut visibility, material) in
+        renderers.iter_mut()
+    {
+        // Remote players are despawned when they leave the area of interest,
+        // so their renderers are removed with them
+        let Ok((player_transform, health, dead)) = players.get(**player_entity) else {
+            commands.entity(renderer_entity).despawn();
             continue;
         };
 
         interpolate_transform(&mut renderer_transform, player_transform, 1.0);
+
+        *visibility = if dead.is_some() {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+
+        // Fade towards red as the player loses health
+        let health = health.map_or(1.0, Health::fraction);
+        let color = Color::rgb(1.0, health, health);
+        if let Some(material) = materials.get_mut(material) {
+            if material.color != color {
+                material.color = color;
+            }
+        }
     }
 }
@@ -0,0 +1,483 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_renet::renet::{ClientId, RenetServer};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    channels::ServerChannel,
+    interest::InterestSettings,
+    messages::{NetworkId, ServerMessage},
+    remote_state::RemoteBulletState,
+};
+
+/// Index of a replicated component type, assigned in registration order.
+type ComponentKind = u16;
+
+/// Replicates registered components of entities marked with `Replicated` from
+/// the server to clients. The same variant must not be added to both sides of
+/// a connection.
+pub enum ReplicationPlugin {
+    Server,
+    Client,
+}
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.configure_sets(
+            PostUpdate,
+            (
+                ReplicationSet::Prepare,
+                ReplicationSet::Process,
+                ReplicationSet::Finish,
+            )
+                .chain(),
+        );
+
+        match self {
+            Self::Server => {
+                app.insert_resource(NetworkIdAllocator::default())
+                    .insert_resource(ServerReplication::default())
+                    .add_systems(
+                        PostUpdate,
+                        (
+                            (assign_network_ids, update_visibility).in_set(ReplicationSet::Prepare),
+                            apply_deferred
+                                .after(ReplicationSet::Prepare)
+                                .before(ReplicationSet::Process),
+                            send_replication.in_set(ReplicationSet::Finish),
+                        ),
+                    );
+            }
+            Self::Client => {
+                app.insert_resource(ClientReplication::default())
+                    .add_systems(
+                        PostUpdate,
+                        (
+                            receive_replication.in_set(ReplicationSet::Prepare),
+                            discard_unknown_components.in_set(ReplicationSet::Finish),
+                        ),
+                    );
+            }
+        }
+
+        // Component kinds are assigned in this order on both sides, so new
+        // types must only ever be added here
+        Registrar::new(self, app).register::<RemoteBulletState>();
+    }
+}
+
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+enum ReplicationSet {
+    Prepare,
+    Process,
+    Finish,
+}
+
+/// A component that can be replicated once registered.
+pub trait ReplicatedComponent: Component + Serialize + DeserializeOwned {}
+
+impl<T: Component + Serialize + DeserializeOwned> ReplicatedComponent for T {}
+
+/// Marks a server entity to be replicated to clients. It is given a
+/// `NetworkId` and its registered components are sent whenever they change.
+/// Entities with a `Transform` are only sent to clients whose player is within
+/// the interest radius of them.
+#[derive(Component, Default)]
+pub struct Replicated;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ReplicationMessage {
+    Spawn {
+        id: NetworkId,
+        components: Vec<(ComponentKind, Vec<u8>)>,
+    },
+    Update {
+        id: NetworkId,
+        components: Vec<(ComponentKind, Vec<u8>)>,
+    },
+    Despawn {
+        id: NetworkId,
+    },
+}
+
+/// Kind assigned to the component type `T`.
+#[derive(Resource)]
+struct KindOf<T> {
+    kind: ComponentKind,
+    marker: PhantomData<fn() -> T>,
+}
+
+struct Registrar<'a> {
+    plugin: &'a ReplicationPlugin,
+    app: &'a mut App,
+    next_kind: ComponentKind,
+}
+
+impl<'a> Registrar<'a> {
+    fn new(plugin: &'a ReplicationPlugin, app: &'a mut App) -> Self {
+        Self {
+            plugin,
+            app,
+            next_kind: 0,
+        }
+    }
+
+    fn register<T: ReplicatedComponent>(&mut self) -> &mut Self {
+        self.app.insert_resource(KindOf::<T> {
+            kind: self.next_kind,
+            marker: PhantomData,
+        });
+        self.next_kind += 1;
+
+        match self.plugin {
+            ReplicationPlugin::Server => self.app.add_systems(
+                PostUpdate,
+                collect_components::<T>.in_set(ReplicationSet::Process),
+            ),
+            ReplicationPlugin::Client => self.app.add_systems(
+                PostUpdate,
+                apply_components::<T>.in_set(ReplicationSet::Process),
+            ),
+        };
+
+        self
+    }
+}
+
+/// Hands out unique network IDs on the server.
+#[derive(Resource, Default)]
+pub struct NetworkIdAllocator {
+    next: u32,
+}
+
+impl NetworkIdAllocator {
+    pub fn allocate(&mut self) -> NetworkId {
+        let id = NetworkId(self.next);
+        self.next = self.next.wrapping_add(1);
+        id
+    }
+}
+
+/// A component serialized on the server this frame.
+struct CollectedComponent {
+    kind: ComponentKind,
+    data: Vec<u8>,
+    changed: bool,
+}
+
+/// Replicated entities a client is sent.
+#[derive(Default)]
+struct ClientView {
+    /// Where the client's player is, or `None` until it has one.
+    viewer: Option<Vec2>,
+
+    /// Entities the client has been sent and not told to despawn.
+    visible: HashSet<Entity>,
+
+    /// Entities that became relevant to the client this frame, which it is
+    /// sent in full.
+    entering: Vec<Entity>,
+
+    /// Entities that stopped being relevant to the client this frame.
+    leaving: Vec<Entity>,
+}
+
+#[derive(Resource, Default)]
+pub struct ServerReplication {
+    /// Clients that replication messages are sent to.
+    clients: HashMap<ClientId, ClientView>,
+
+    /// Network IDs of replicated entities, kept to announce their despawn.
+    network_ids: HashMap<Entity, NetworkId>,
+
+    /// Entities that some client is sent in full this frame.
+    entering: HashSet<Entity>,
+
+    collected: HashMap<Entity, Vec<CollectedComponent>>,
+}
+
+impl ServerReplication {
+    /// Starts replicating to a client, beginning with the current state of every
+    /// replicated entity relevant to it.
+    pub fn add_client(&mut self, client_id: ClientId) {
+        self.clients.entry(client_id).or_default();
+    }
+
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        self.clients.remove(&client_id);
+    }
+
+    /// Moves the point that a client's area of interest is centered on.
+    pub fn set_viewer(&mut self, client_id: ClientId, position: Vec2) {
+        if let Some(view) = self.clients.get_mut(&client_id) {
+            view.viewer = Some(position);
+        }
+    }
+}
+
+fn assign_network_ids(
+    mut commands: Commands,
+    mut network_ids: ResMut<NetworkIdAllocator>,
+    mut replication: ResMut<ServerReplication>,
+    entities: Query<Entity, (With<Replicated>, Without<NetworkId>)>,
+) {
+    for entity in entities.iter() {
+        let network_id = network_ids.allocate();
+        commands.entity(entity).insert(network_id);
+        replication.network_ids.insert(entity, network_id);
+    }
+}
+
+/// Works out which entities each client starts and stops being sent.
+fn update_visibility(
+    settings: Res<InterestSettings>,
+    mut replication: ResMut<ServerReplication>,
+    entities: Query<(Entity, Option<&Transform>), With<Replicated>>,
+) {
+    let replication = &mut *replication;
+    replication.entering.clear();
+
+    for view in replication.clients.values_mut() {
+        view.entering.clear();
+        view.leaving.clear();
+
+        for (entity, transform) in entities.iter() {
+            let relevant = match (transform, view.viewer) {
+                (None, _) => true,
+                (Some(transform), Some(viewer)) => {
+                    viewer.distance(transform.translation.xy()) <= settings.radius
+                }
+                (Some(_), None) => false,
+            };
+
+            if relevant && view.visible.insert(entity) {
+                view.entering.push(entity);
+                replication.entering.insert(entity);
+            } else if !relevant && view.visible.remove(&entity) {
+                view.leaving.push(entity);
+            }
+        }
+    }
+}
+
+fn collect_components<T: ReplicatedComponent>(
+    kind: Res<KindOf<T>>,
+    mut replication: ResMut<ServerReplication>,
+    components: Query<(Entity, Ref<T>, Ref<NetworkId>), With<Replicated>>,
+) {
+    for (entity, component, network_id) in components.iter() {
+        // Clients that just started seeing an entity need every component,
+        // not just the ones that changed
+        let changed = component.is_changed() || network_id.is_added();
+        if !changed && !replication.entering.contains(&entity) {
+            continue;
+        }
+
+        let data = match bincode::serialize(&*component) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Failed to serialize replicated component: {}", err);
+                continue;
+            }
+        };
+        replication
+            .collected
+            .entry(entity)
+            .or_default()
+            .push(CollectedComponent {
+                kind: kind.kind,
+                data,
+                changed,
+            });
+    }
+}
+
+fn send_replication(
+    mut server: ResMut<RenetServer>,
+    mut replication: ResMut<ServerReplication>,
+    entities: Query<(Entity, &NetworkId), With<Replicated>>,
+    mut removed: RemovedComponents<Replicated>,
+) {
+    let replication = &mut *replication;
+    let mut collected = std::mem::take(&mut replication.collected);
+
+    let mut spawns = HashMap::new();
+    let mut updates = HashMap::new();
+    for (entity, network_id) in entities.iter() {
+        let components = collected.remove(&entity).unwrap_or_default();
+        let changed: Vec<_> = components
+            .iter()
+            .filter(|component| component.changed)
+            .map(|component| (component.kind, component.data.clone()))
+            .collect();
+        if !changed.is_empty() {
+            updates.insert(
+                entity,
+                ReplicationMessage::Update {
+                    id: *network_id,
+                    components: changed,
+                },
+            );
+        }
+        if replication.entering.contains(&entity) {
+            spawns.insert(
+                entity,
+                ReplicationMessage::Spawn {
+                    id: *network_id,
+                    components: components
+                        .into_iter()
+                        .map(|component| (component.kind, component.data))
+                        .collect(),
+                },
+            );
+        }
+    }
+
+    // Despawned entities are forgotten by every client that was sent them
+    let despawned: Vec<_> = removed
+        .read()
+        .filter_map(|entity| Some((entity, replication.network_ids.remove(&entity)?)))
+        .collect();
+
+    for (client_id, view) in replication.clients.iter_mut() {
+        // Clients that just started seeing an entity are sent all of it
+        // instead of this frame's changes
+        let mut messages: Vec<_> = view
+            .entering
+            .iter()
+            .filter_map(|entity| spawns.get(entity).cloned())
+            .collect();
+        messages.extend(
+            updates
+                .iter()
+                .filter(|(entity, _)| {
+                    view.visible.contains(*entity) && !view.entering.contains(*entity)
+                })
+                .map(|(_, message)| message.clone()),
+        );
+
+        messages.extend(
+            view.leaving
+                .iter()
+                .filter_map(|entity| replication.network_ids.get(entity))
+                .map(|id| ReplicationMessage::Despawn { id: *id }),
+        );
+        for (entity, id) in despawned.iter() {
+            if view.visible.remove(entity) {
+                messages.push(ReplicationMessage::Despawn { id: *id });
+            }
+        }
+
+        if messages.is_empty() {
+            continue;
+        }
+        match bincode::serialize(&ServerMessage::Replication(messages)) {
+            Ok(bytes) => server.send_message(*client_id, ServerChannel::ServerMessages, bytes),
+            Err(err) => warn!("Failed to serialize replication message: {}", err),
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ClientReplication {
+    /// Maps network IDs to the entities replicating them on this client.
+    entities: HashMap<NetworkId, Entity>,
+
+    /// Messages received from the server that have not been applied yet.
+    inbox: Vec<ReplicationMessage>,
+
+    /// Component data waiting to be inserted by the system for its kind.
+    pending: Vec<(Entity, ComponentKind, Vec<u8>)>,
+}
+
+impl ClientReplication {
+    pub fn receive(&mut self, messages: Vec<ReplicationMessage>) {
+        self.inbox.extend(messages);
+    }
+}
+
+fn receive_replication(mut commands: Commands, mut replication: ResMut<ClientReplication>) {
+    let replication = &mut *replication;
+
+    for message in replication.inbox.drain(..) {
+        match message {
+            ReplicationMessage::Spawn { id, components } => {
+                let entity = *replication
+                    .entities
+                    .entry(id)
+                    .or_insert_with(|| commands.spawn(id).id());
+                replication.pending.extend(
+                    components
+                        .into_iter()
+                        .map(|(kind, data)| (entity, kind, data)),
+                );
+            }
+            ReplicationMessage::Update { id, components } => {
+                let Some(entity) = replication.entities.get(&id).copied() else {
+                    warn!("Received update for unknown network ID {:?}", id);
+                    continue;
+                };
+                replication.pending.extend(
+                    components
+                        .into_iter()
+                        .map(|(kind, data)| (entity, kind, data)),
+                );
+            }
+            ReplicationMessage::Despawn { id } => {
+                let Some(entity) = replication.entities.remove(&id) else {
+                    continue;
+                };
+                replication
+                    .pending
+                    .retain(|(pending_entity, _, _)| *pending_entity != entity);
+
+                // The entity may have already been despawned locally
+                if let Some(entity_commands) = commands.get_entity(entity) {
+                    entity_commands.despawn_recursive();
+                }
+            }
+        }
+    }
+}
+
+fn apply_components<T: ReplicatedComponent>(
+    mut commands: Commands,
+    kind: Res<KindOf<T>>,
+    mut replication: ResMut<ClientReplication>,
+) {
+    replication
+        .pending
+        .retain(|(entity, component_kind, data)| {
+            if *component_kind != kind.kind {
+                return true;
+            }
+
+            match bincode::deserialize::<T>(data) {
+                Ok(component) => {
+                    let entity = *entity;
+                    commands.add(move |world: &mut World| {
+                        // Entities can be despawned locally before this is applied
+                        if let Some(mut entity) = world.get_entity_mut(entity) {
+                            entity.insert(component);
+                        }
+                    });
+                }
+                Err(err) => warn!("Failed to deserialize replicated component: {}", err),
+            }
+            false
+        });
+}
+
+fn discard_unknown_components(mut replication: ResMut<ClientReplication>) {
+    if !replication.pending.is_empty() {
+        warn!(
+            "Discarding {} replicated components of unknown kind",
+            replication.pending.len()
+        );
+        replication.pending.clear();
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::MAX_QUANTIZED_SPEED;
+
+/// Longest dead players may wait before respawning, in seconds.
+const MAX_RESPAWN_DELAY: f32 = 60.0;
+
+/// Longest a match may last, in seconds.
+const MAX_TIME_LIMIT: f32 = 24.0 * 60.0 * 60.0;
+
+/// Longest the countdown before a match may last, in seconds.
+const MAX_COUNTDOWN: f32 = 60.0;
+
+/// Longest the results may be shown after a match, in seconds.
+const MAX_POST_MATCH_DELAY: f32 = 300.0;
+
+/// How matches are won.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum GameMode {
+    /// Every player for themselves. The player with the most kills wins.
+    #[default]
+    Deathmatch,
+
+    /// Players are split into two teams, which win by their combined kills.
+    /// Teammates can't hurt each other.
+    TeamDeathmatch,
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Deathmatch => write!(f, "Deathmatch"),
+            Self::TeamDeathmatch => write!(f, "Team deathmatch"),
+        }
+    }
+}
+
+/// Rules of the game, chosen by the server. Clients receive them when they
+/// join, since prediction has to move players the same way the server does.
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameRules {
+    /// Top speed of players, in units per second.
+    pub player_speed: f32,
+
+    /// Health players spawn with.
+    pub max_health: f32,
+
+    /// Seconds dead players wait before respawning.
+    pub respawn_delay: f32,
+
+    pub mode: GameMode,
+
+    /// Kills a player, or a team in team deathmatch, needs to win the match.
+    /// Zero for no limit.
+    pub score_limit: u32,
+
+    /// Seconds a match lasts before the leader wins. Zero for no limit.
+    pub time_limit: f32,
+
+    /// Number of players, counting bots, needed to start a match. Until then
+    /// players warm up without keeping score.
+    pub min_players: usize,
+
+    /// Seconds counted down before a match starts.
+    pub countdown: f32,
+
+    /// Seconds the results are shown after a match before warmup begins
+    /// again.
+    pub post_match_delay: f32,
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        Self {
+            player_speed: 15.0,
+            max_health: 100.0,
+            respawn_delay: 3.0,
+            mode: GameMode::Deathmatch,
+            score_limit: 20,
+            time_limit: 300.0,
+            min_players: 2,
+            countdown: 5.0,
+            post_match_delay: 10.0,
+        }
+    }
+}
+
+impl GameRules {
+    /// Checks that the rules describe a playable game, returning a readable
+    /// description of the first problem otherwise.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.player_speed.is_finite() && self.player_speed >= 0.0) {
+            return Err(format!(
+                "player_speed must be zero or more, not {}",
+                self.player_speed
+            ));
+        }
+        // Snapshots couldn't carry the velocity of faster players
+        if self.player_speed > MAX_QUANTIZED_SPEED {
+            return Err(format!(
+                "player_speed must be at most {}, not {}",
+                MAX_QUANTIZED_SPEED, self.player_speed
+            ));
+        }
+        if !(self.max_health.is_finite() && self.max_health > 0.0) {
+            return Err(format!(
+                "max_health must be more than zero, not {}",
+                self.max_health
+            ));
+        }
+        if !(0.0..=MAX_RESPAWN_DELAY).contains(&self.respawn_delay) {
+            return Err(format!(
+                "respawn_delay must be from 0 to {} seconds, not {}",
+                MAX_RESPAWN_DELAY, self.respawn_delay
+            ));
+        }
+        if !(0.0..=MAX_TIME_LIMIT).contains(&self.time_limit) {
+            return Err(format!(
+                "time_limit must be from 0 to {} seconds, not {}",
+                MAX_TIME_LIMIT, self.time_limit
+            ));
+        }
+        // Otherwise a match would never end
+        if self.score_limit == 0 && self.time_limit == 0.0 {
+            return Err("score_limit and time_limit can't both be zero".to_string());
+        }
+        if self.min_players == 0 {
+            return Err("min_players must be at least 1".to_string());
+        }
+        if !(0.0..=MAX_COUNTDOWN).contains(&self.countdown) {
+            return Err(format!(
+                "countdown must be from 0 to {} seconds, not {}",
+                MAX_COUNTDOWN, self.countdown
+            ));
+        }
+        if !(0.0..=MAX_POST_MATCH_DELAY).contains(&self.post_match_delay) {
+            return Err(format!(
+                "post_match_delay must be from 0 to {} seconds, not {}",
+                MAX_POST_MATCH_DELAY, self.post_match_delay
+            ));
+        }
+        Ok(())
+    }
+}
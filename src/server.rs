@@ -1,4 +1,10 @@
-use bevy::{log::LogPlugin, prelude::*, utils::hashbrown::HashMap};
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    ecs::system::SystemParam,
+    log::LogPlugin,
+    prelude::*,
+    utils::{hashbrown::HashMap, HashSet},
+};
 use bevy_renet::{
     renet::{
         transport::{NetcodeServerTransport, ServerAuthentication, ServerConfig},
@@ -7,22 +13,51 @@ use bevy_renet::{
     transport::NetcodeServerPlugin,
     RenetServerPlugin,
 };
-use renet::DefaultChannel;
-use std::{net::UdpSocket, time::SystemTime};
+use std::{
+    collections::VecDeque,
+    net::UdpSocket,
+    process,
+    time::{Duration, SystemTime},
+};
 
 use crate::{
-    messages::{ClientMessage, ServerMessage},
-    player_controller::{PlayerController, PlayerControllerPlugin},
+    ai::{balance_bots, think, AiSettings},
+    auth::{username_from_user_data, PrivateKey, PROTOCOL_ID},
+    bullet::{fire_bullets, update_bullets},
+    channels::{ClientChannel, ServerChannel},
+    clock::epoch_millis,
+    config::ServerSettings,
+    game_match::{
+        advance_match, broadcast_match_status, count_kills, join_match, start_match, update_scores,
+        weapons_allowed, MatchStatus,
+    },
+    health::{apply_damage, respawn_players, DamageEvent, Dead, DeathEvent, Health, SpawnPoints},
+    interest::{InterestSettings, PriorityAccumulator},
+    lag_compensation::{record_positions, LagCompensationSettings, PositionHistory, ViewRewind},
+    map::Map,
+    messages::{
+        ClientMessage, Features, HandshakeResponse, PlayerStatus, RejectReason, ServerMessage,
+        PROTOCOL_VERSION,
+    },
+    player::PlayerName,
+    player_controller::{ControlSet, PlayerController, PlayerControllerPlugin, PlayerInput},
+    replication::{ReplicationPlugin, ServerReplication},
+    rules::GameRules,
+    snapshot::{PlayerStates, QuantizedPlayerState, SnapshotEncoder},
+};
+use crate::{
+    remote_state::RemotePlayerState,
+    tick::{Tick, TickRate},
+    weapon::{Weapon, Weapons},
+    GameState,
 };
-use crate::{remote_state::RemotePlayerState, GameState};
 
 pub fn make_connection_config() -> ConnectionConfig {
-    ConnectionConfig::default()
-    // ConnectionConfig {
-    //     available_bytes_per_tick: 1024 * 1024,
-    //     client_channels_config: ClientChannel::channels_config(),
-    //     server_channels_config: ServerChannel::channels_config(),
-    // }
+    ConnectionConfig {
+        available_bytes_per_tick: 1024 * 1024,
+        client_channels_config: ClientChannel::channels_config(),
+        server_channels_config: ServerChannel::channels_config(),
+    }
 }
 
 // Maps client IDs to player entities.
@@ -32,158 +67,663 @@ pub struct ClientMap(HashMap<ClientId, Entity>);
 // Maps player entities to client IDs. Attached as a component to player
 // entities.
 #[derive(Component, Deref, DerefMut)]
-pub struct PlayerClient(ClientId);
+pub struct PlayerClient(pub ClientId);
+
+// Snapshot history of each client, used to delta-encode the snapshots sent to
+// it.
+#[derive(Deref, DerefMut, Resource, Default)]
+pub struct ClientSnapshots(HashMap<ClientId, SnapshotEncoder>);
+
+// Priorities of the players relevant to each client, used to decide which of
+// them are sent on a tick.
+#[derive(Deref, DerefMut, Resource, Default)]
+pub struct ClientInterests(HashMap<ClientId, PriorityAccumulator>);
+
+// Sends messages about a player only to the clients it is relevant to, so that
+// clients can't learn about players outside of their area of interest.
+#[derive(SystemParam)]
+pub struct PlayerMessages<'w> {
+    server: ResMut<'w, RenetServer>,
+    client_interests: Res<'w, ClientInterests>,
+}
+
+impl PlayerMessages<'_> {
+    pub fn send(&mut self, player: ClientId, bytes: Vec<u8>) {
+        // Players are always relevant to their own client
+        for (client_id, interest) in self.client_interests.iter() {
+            if *client_id == player || interest.contains(player) {
+                self.server
+                    .send_message(*client_id, ServerChannel::ServerMessages, bytes.clone());
+            }
+        }
+    }
+}
+
+// Number of ticks between the snapshots sent to clients.
+#[derive(Deref, Resource, Clone, Copy)]
+pub struct SnapshotInterval(u32);
 
-pub fn run_server(port: u16, connection_config: ConnectionConfig) {
-    let server_addr = format!("0.0.0.0:{}", port).parse().unwrap();
+// How long clients have to complete the handshake before they are
+// disconnected. Rejected clients are expected to disconnect on their own, and
+// are kicked once this runs out as well.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How the server runs the game, as told to clients when they join.
+#[derive(SystemParam)]
+struct GameSetup<'w> {
+    tick_rate: Res<'w, TickRate>,
+    rules: Res<'w, GameRules>,
+    map: Res<'w, Map>,
+    weapons: Res<'w, Weapons>,
+    spawn_points: Res<'w, SpawnPoints>,
+    match_status: Res<'w, MatchStatus>,
+}
+
+// Clients that have connected but have not been accepted yet, with the time
+// they connected at. They have no player and are not sent any game state.
+#[derive(Deref, DerefMut, Resource, Default)]
+pub struct PendingHandshakes(HashMap<ClientId, Duration>);
+
+// Sent when a client introduces itself.
+#[derive(Event)]
+struct HelloEvent {
+    client_id: ClientId,
+    version: u32,
+    features: Features,
+}
+
+// Maximum number of inputs buffered for a player. Older inputs are dropped if
+// a client sends faster than the server simulates.
+const MAX_QUEUED_INPUTS: usize = 8;
+
+// Inputs received from a client that are waiting to be simulated. One input is
+// applied per fixed update so that the client can replay them identically.
+#[derive(Component, Default)]
+pub struct InputQueue {
+    pending: VecDeque<PlayerInput>,
+
+    /// Sequence number of the last input received. Inputs that are not newer
+    /// than this are ignored.
+    last_received: u32,
+
+    /// Sequence number of the last input applied to the player controller.
+    last_applied: u32,
+}
+
+pub fn run_server(
+    settings: ServerSettings,
+    map: Map,
+    weapons: Weapons,
+    private_key: Option<PrivateKey>,
+    connection_config: ConnectionConfig,
+) {
+    let tick_rate = settings.tick_rate;
+
+    // Without a private key, clients can connect with any client ID
+    let authentication = match private_key {
+        Some(private_key) => ServerAuthentication::Secure { private_key },
+        None => {
+            println!("No private key given, accepting unauthenticated clients");
+            ServerAuthentication::Unsecure
+        }
+    };
     let server_config = ServerConfig {
         current_time: SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap(),
-        max_clients: 64,
-        protocol_id: 0,
-        public_addresses: vec![server_addr],
-        authentication: ServerAuthentication::Unsecure,
+        max_clients: settings.max_clients,
+        protocol_id: PROTOCOL_ID,
+        public_addresses: settings.public_addresses(),
+        authentication,
+    };
+    let socket = match UdpSocket::bind(settings.bind_address) {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("Failed to bind to {}: {}", settings.bind_address, err);
+            process::exit(1);
+        }
     };
-    let socket = UdpSocket::bind(server_addr).unwrap();
-    println!("Started server on {:?}", socket.local_addr());
+    let transport = match NetcodeServerTransport::new(server_config, socket) {
+        Ok(transport) => transport,
+        Err(err) => {
+            eprintln!("Failed to start server: {}", err);
+            process::exit(1);
+        }
+    };
+    println!(
+        "Started server on {} playing {}",
+        settings.bind_address, map.name
+    );
+
+    // Snapshots can only be sent on ticks, so the send rate is rounded to a
+    // whole number of ticks between them
+    let snapshot_interval = (tick_rate / settings.send_rate).round().max(1.0) as u32;
 
     App::new()
         .add_plugins((
-            MinimalPlugins,
+            // Sleep between frames instead of spinning, since nothing happens
+            // between ticks that can't wait for the next one
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                1.0 / tick_rate,
+            ))),
             LogPlugin::default(),
             TransformPlugin,
             HierarchyPlugin,
             RenetServerPlugin,
             NetcodeServerPlugin,
             PlayerControllerPlugin { headless: true },
+            ReplicationPlugin::Server,
         ))
         .add_state::<GameState>()
         .insert_resource(ClientMap::default())
+        .insert_resource(PendingHandshakes::default())
+        .insert_resource(ClientSnapshots::default())
+        .insert_resource(ClientInterests::default())
+        .insert_resource(settings.interest)
+        .insert_resource(Time::<Fixed>::from_hz(tick_rate))
+        .insert_resource(TickRate(tick_rate))
+        .insert_resource(SnapshotInterval(snapshot_interval))
+        .insert_resource(Tick::default())
+        .insert_resource(SpawnPoints::new(map.spawn_points.clone()))
+        .insert_resource(map)
+        .insert_resource(weapons)
+        .insert_resource(LagCompensationSettings {
+            max_rewind: Duration::from_millis(settings.max_rewind),
+        })
+        .insert_resource(AiSettings {
+            fill_to: settings.bots,
+            ..default()
+        })
+        .insert_resource(settings.rules)
+        .insert_resource(MatchStatus::default())
+        .add_event::<DamageEvent>()
+        .add_event::<DeathEvent>()
+        .add_event::<HelloEvent>()
         .insert_resource(RenetServer::new(connection_config))
-        .insert_resource(NetcodeServerTransport::new(server_config, socket).unwrap())
+        .insert_resource(transport)
+        .add_systems(
+            FixedUpdate,
+            (
+                server_advance_tick.before(ControlSet::Read),
+                server_apply_inputs.in_set(ControlSet::Read),
+                think.in_set(ControlSet::Read),
+                (
+                    record_positions,
+                    fire_bullets.run_if(weapons_allowed),
+                    update_bullets,
+                    apply_damage,
+                    count_kills,
+                    respawn_players,
+                    server_broadcast,
+                    server_send_player_statuses,
+                )
+                    .chain()
+                    .after(ControlSet::Apply),
+            ),
+        )
         .add_systems(
             Update,
             (
-                server_receive,
+                (server_receive, server_handle_handshakes).chain(),
                 server_handle_network_events,
-                server_broadcast,
+                server_expire_handshakes,
+                server_update_viewers,
+                balance_bots,
+                (
+                    join_match,
+                    update_scores,
+                    advance_match,
+                    broadcast_match_status,
+                )
+                    .chain(),
             ),
         )
+        .add_systems(OnEnter(GameState::InProgress), start_match)
         .run();
 }
 
+// Name a client connected with. Secure clients carry the username they were
+// issued a token for, while unsecure clients choose their own.
+fn client_name(transport: &NetcodeServerTransport, client_id: ClientId) -> Option<String> {
+    transport
+        .user_data(client_id)
+        .and_then(|user_data| username_from_user_data(&user_data))
+        .map(|username| username.trim().to_string())
+        .filter(|username| !username.is_empty())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn server_handle_network_events(
     mut commands: Commands,
+    time: Res<Time>,
     mut client_map: ResMut<ClientMap>,
+    mut pending_handshakes: ResMut<PendingHandshakes>,
     mut events: EventReader<ServerEvent>,
     mut server: ResMut<RenetServer>,
+    mut replication: ResMut<ServerReplication>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
+    mut client_interests: ResMut<ClientInterests>,
+    transport: Res<NetcodeServerTransport>,
 ) {
     for event in events.read() {
         // handle events
         match event {
             ServerEvent::ClientConnected { client_id } => {
-                println!("Player {} connected.", client_id);
-
-                // Spawn the player
-                let player_entity = commands
-                    .spawn((
-                        PlayerClient(*client_id),
-                        PlayerController::default(),
-                        TransformBundle::default(),
-                    ))
-                    .id();
-                client_map.insert(*client_id, player_entity);
-
-                // broadcast a message to inform other clients of the new player
-                let new_player_message = bincode::serialize(&ServerMessage::PlayerConnected {
-                    client_id: *client_id,
-                })
-                .unwrap();
-                server.broadcast_message(DefaultChannel::ReliableOrdered, new_player_message);
+                match client_name(&transport, *client_id) {
+                    Some(username) => println!("Player {} ({}) connected.", client_id, username),
+                    None => println!("Player {} connected.", client_id),
+                }
+
+                // The player is spawned once the client's hello is accepted
+                pending_handshakes.insert(*client_id, time.elapsed());
             }
             ServerEvent::ClientDisconnected { client_id, reason } => {
                 println!("Player {} disconnected: {}", client_id, reason);
+                pending_handshakes.remove(client_id);
+                replication.remove_client(*client_id);
+                client_snapshots.remove(client_id);
+                client_interests.remove(client_id);
+
+                // Clients that never got past the handshake have no player
+                let Some(player_entity) = client_map.remove(client_id) else {
+                    continue;
+                };
+                commands.entity(player_entity).despawn();
 
                 // broadcast player disconnection
                 let disconnect_message = bincode::serialize(&ServerMessage::PlayerDisconnected {
                     client_id: *client_id,
                 })
                 .unwrap();
-                server.broadcast_message(DefaultChannel::ReliableOrdered, disconnect_message);
+                server.broadcast_message(ServerChannel::ServerMessages, disconnect_message);
             }
         }
     }
 }
 
-fn server_receive(
+// Accepts clients whose hello matches this server's protocol and spawns their
+// player, or tells them why they were rejected.
+#[allow(clippy::too_many_arguments)]
+fn server_handle_handshakes(
     mut commands: Commands,
+    mut hellos: EventReader<HelloEvent>,
+    mut server: ResMut<RenetServer>,
+    game: GameSetup,
+    mut pending_handshakes: ResMut<PendingHandshakes>,
+    mut client_map: ResMut<ClientMap>,
+    mut replication: ResMut<ServerReplication>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
+    mut client_interests: ResMut<ClientInterests>,
+    transport: Res<NetcodeServerTransport>,
+    players: Query<(&PlayerClient, &PlayerName, &Health, Option<&Dead>)>,
+) {
+    for hello in hellos.read() {
+        let client_id = &hello.client_id;
+        if !pending_handshakes.contains_key(client_id) {
+            continue;
+        }
+
+        let response = if hello.version != PROTOCOL_VERSION {
+            HandshakeResponse::Rejected(RejectReason::VersionMismatch {
+                server_version: PROTOCOL_VERSION,
+            })
+        } else if !hello.features.contains(Features::REQUIRED) {
+            HandshakeResponse::Rejected(RejectReason::MissingFeatures(Features::REQUIRED))
+        } else {
+            HandshakeResponse::Accepted {
+                features: hello.features.intersection(Features::SUPPORTED),
+            }
+        };
+        let accepted = matches!(response, HandshakeResponse::Accepted { .. });
+
+        let mut messages = vec![ServerMessage::Handshake(response)];
+        if accepted {
+            messages.push(ServerMessage::ServerInfo {
+                tick_rate: **game.tick_rate,
+                rules: game.rules.clone(),
+            });
+            messages.push(ServerMessage::Map(game.map.clone()));
+            messages.push(ServerMessage::MatchStatus(game.match_status.clone()));
+        }
+        for message in messages {
+            match bincode::serialize(&message) {
+                Ok(bytes) => server.send_message(*client_id, ServerChannel::ServerMessages, bytes),
+                Err(err) => warn!("Failed to serialize handshake message: {}", err),
+            }
+        }
+
+        // Rejected clients stay pending until they disconnect or time out
+        if !accepted {
+            println!(
+                "Rejected player {} running protocol version {}.",
+                client_id, hello.version
+            );
+            continue;
+        }
+        pending_handshakes.remove(client_id);
+        let name =
+            client_name(&transport, *client_id).unwrap_or_else(|| format!("Player {}", client_id));
+
+        // Spawn the player
+        let player_entity = commands
+            .spawn((
+                PlayerClient(*client_id),
+                PlayerName(name.clone()),
+                PlayerController::default(),
+                InputQueue::default(),
+                Weapon::new(&game.weapons),
+                Health::new(game.rules.max_health),
+                PositionHistory::default(),
+                ViewRewind::default(),
+                TransformBundle::from_transform(Transform::from_translation(
+                    game.spawn_points.choose().extend(0.0),
+                )),
+            ))
+            .id();
+        client_map.insert(*client_id, player_entity);
+        replication.add_client(*client_id);
+        client_snapshots.insert(*client_id, SnapshotEncoder::default());
+        client_interests.insert(*client_id, PriorityAccumulator::default());
+
+        // Let the new client know the state of everyone already playing.
+        // The new player isn't spawned until commands are applied, so
+        // it isn't included.
+        let connected_players = players
+            .iter()
+            .map(|(player_client, name, health, dead)| {
+                (
+                    **player_client,
+                    PlayerStatus {
+                        name: name.to_string(),
+                        health: health.current,
+                        dead: dead.is_some(),
+                    },
+                )
+            })
+            .collect();
+        match bincode::serialize(&ServerMessage::ConnectedPlayers(connected_players)) {
+            Ok(bytes) => server.send_message(*client_id, ServerChannel::ServerMessages, bytes),
+            Err(err) => warn!("Failed to serialize connected players message: {}", err),
+        }
+
+        // broadcast a message to inform other clients of the new player
+        let new_player_message = bincode::serialize(&ServerMessage::PlayerConnected {
+            client_id: *client_id,
+            name,
+        })
+        .unwrap();
+        server.broadcast_message(ServerChannel::ServerMessages, new_player_message);
+    }
+}
+
+// Disconnects clients that have not completed the handshake in time.
+fn server_expire_handshakes(
+    time: Res<Time>,
+    mut server: ResMut<RenetServer>,
+    mut pending_handshakes: ResMut<PendingHandshakes>,
+) {
+    pending_handshakes.retain(|client_id, connected_at| {
+        if time.elapsed() - *connected_at < HANDSHAKE_TIMEOUT {
+            return true;
+        }
+
+        println!(
+            "Player {} did not complete the handshake in time.",
+            client_id
+        );
+        server.disconnect(*client_id);
+        false
+    });
+}
+
+fn server_receive(
     mut server: ResMut<RenetServer>,
     client_map: Res<ClientMap>,
+    pending_handshakes: Res<PendingHandshakes>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
+    mut input_queues: Query<&mut InputQueue>,
+    mut hellos: EventWriter<HelloEvent>,
 ) {
+    // Messages are only read once per frame, so pings count as received at the
+    // start of it
+    let receive_time = epoch_millis();
+
+    // Hellos and inputs arrive reliably on Input, while pings are sent
+    // unreliably on Ping.
     for client_id in server.clients_id() {
-        while let Some(bytes) = server.receive_message(client_id, DefaultChannel::ReliableOrdered) {
-            // Retrieve player entity and commands for this client
-            //
-            // TODO: these would be better placed outside the above while loop,
-            // but this causes the values to be looked up for every client even
-            // when they have not sent any messages.
-            let Some(player_entity) = client_map.get(&client_id) else {
-                warn!(
-                    "Received controls from client that has no mapped ID (client ID: {})",
-                    client_id
-                );
-                continue;
-            };
-            let Some(mut player_commands) = commands.get_entity(*player_entity) else {
-                warn!(
-                    "Received controls from client whose mapped entity is missing (client ID: {})",
-                    client_id
-                );
-                continue;
-            };
+        for channel in [ClientChannel::Input, ClientChannel::Ping] {
+            while let Some(bytes) = server.receive_message(client_id, channel) {
+                let msg: ClientMessage = match bincode::deserialize(&bytes) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        // Clients running another version are expected to send
+                        // messages that can't be read until they are rejected
+                        if !pending_handshakes.contains_key(&client_id) {
+                            warn!("Failed to deserialize client message: {}", err);
+                        }
+                        continue;
+                    }
+                };
+
+                let input = match msg {
+                    ClientMessage::Hello { version, features } => {
+                        hellos.send(HelloEvent {
+                            client_id,
+                            version,
+                            features,
+                        });
+                        continue;
+                    }
+                    ClientMessage::Ping { client_time } => {
+                        // Pongs are sent unreliably, since a resent pong would
+                        // overestimate the round trip time
+                        match bincode::serialize(&ServerMessage::Pong {
+                            client_time,
+                            receive_time,
+                            server_time: epoch_millis(),
+                        }) {
+                            Ok(bytes) => {
+                                server.send_message(client_id, ServerChannel::PlayerData, bytes)
+                            }
+                            Err(err) => warn!("Failed to serialize pong message: {}", err),
+                        }
+                        continue;
+                    }
+                    ClientMessage::Input(input) => input,
+                };
 
-            // Update the player controller from client
-            let msg: ClientMessage = match bincode::deserialize(&bytes) {
-                Ok(msg) => msg,
-                Err(err) => {
-                    warn!("Failed to deserialize client message: {}", err);
+                // Retrieve player entity and input queue for this client
+                //
+                // TODO: these would be better placed outside the above while loop,
+                // but this causes the values to be looked up for every client even
+                // when they have not sent any messages.
+                let Some(player_entity) = client_map.get(&client_id) else {
+                    if !pending_handshakes.contains_key(&client_id) {
+                        warn!(
+                            "Received controls from client that has no mapped ID (client ID: {})",
+                            client_id
+                        );
+                    }
+                    continue;
+                };
+                let Ok(mut input_queue) = input_queues.get_mut(*player_entity) else {
+                    warn!(
+                        "Received controls from client whose mapped entity is missing \
+                         (client ID: {})",
+                        client_id
+                    );
+                    continue;
+                };
+
+                // Queue client input to be applied on the next fixed update
+                if input.sequence <= input_queue.last_received {
                     continue;
                 }
-            };
-            match msg {
-                ClientMessage::Controller(controller) => {
-                    player_commands.insert(controller);
+                input_queue.last_received = input.sequence;
+
+                if let Some(encoder) = client_snapshots.get_mut(&client_id) {
+                    encoder.acknowledge(input.snapshot_tick);
                 }
+
+                if input_queue.pending.len() >= MAX_QUEUED_INPUTS {
+                    input_queue.pending.pop_front();
+                }
+                input_queue.pending.push_back(input);
             }
         }
     }
 }
 
+// Centers the area of interest of every client on its player, for replicated
+// entities.
+fn server_update_viewers(
+    mut replication: ResMut<ServerReplication>,
+    players: Query<(&PlayerClient, &Transform)>,
+) {
+    for (player_client, transform) in players.iter() {
+        replication.set_viewer(**player_client, transform.translation.xy());
+    }
+}
+
+// Tells clients the status of players that just became relevant to them, since
+// they weren't sent their damage and deaths while the players were away.
+fn server_send_player_statuses(
+    mut server: ResMut<RenetServer>,
+    client_map: Res<ClientMap>,
+    mut client_interests: ResMut<ClientInterests>,
+    players: Query<(&PlayerName, &Health, Option<&Dead>)>,
+) {
+    for (client_id, interest) in client_interests.iter_mut() {
+        let statuses: Vec<_> = interest
+            .take_entered()
+            .into_iter()
+            .filter(|other_id| other_id != client_id)
+            .filter_map(|other_id| {
+                let (name, health, dead) = players.get(*client_map.get(&other_id)?).ok()?;
+                let status = PlayerStatus {
+                    name: name.to_string(),
+                    health: health.current,
+                    dead: dead.is_some(),
+                };
+                Some((other_id, status))
+            })
+            .collect();
+        if statuses.is_empty() {
+            continue;
+        }
+
+        match bincode::serialize(&ServerMessage::PlayersInRange(statuses)) {
+            Ok(bytes) => server.send_message(*client_id, ServerChannel::ServerMessages, bytes),
+            Err(err) => warn!("Failed to serialize player status message: {}", err),
+        }
+    }
+}
+
+fn server_advance_tick(mut tick: ResMut<Tick>) {
+    **tick += 1;
+}
+
+// Applies the next queued input of every player. If a client's input has not
+// arrived in time, the player keeps acting on its previous input.
+fn server_apply_inputs(
+    tick: Res<Tick>,
+    tick_rate: Res<TickRate>,
+    lag_compensation: Res<LagCompensationSettings>,
+    mut players: Query<(&mut PlayerController, &mut InputQueue, &mut ViewRewind)>,
+) {
+    for (mut controller, mut input_queue, mut view_rewind) in players.iter_mut() {
+        let Some(input) = input_queue.pending.pop_front() else {
+            continue;
+        };
+        controller.apply_input(&input);
+        input_queue.last_applied = input.sequence;
+        **view_rewind = lag_compensation.rewind_ticks(*tick, &input, *tick_rate);
+    }
+}
+
+// Sends every client the state of the players near its own, encoded against
+// the latest snapshot that client has acknowledged. Distant players are sent on
+// fewer snapshots, and players out of range are not sent at all.
 fn server_broadcast(
     mut server: ResMut<RenetServer>,
-    players: Query<(&Transform, &PlayerClient), With<PlayerController>>,
+    tick: Res<Tick>,
+    snapshot_interval: Res<SnapshotInterval>,
+    interest_settings: Res<InterestSettings>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
+    mut client_interests: ResMut<ClientInterests>,
+    players: Query<(
+        &Transform,
+        &PlayerController,
+        Option<&InputQueue>,
+        &PlayerClient,
+    )>,
 ) {
-    let msg = ServerMessage::Players(
-        players
+    if !(**tick).is_multiple_of(**snapshot_interval) {
+        return;
+    }
+
+    let states: Vec<_> = players
+        .iter()
+        .map(|(transform, controller, _, player_client)| {
+            let state = RemotePlayerState {
+                position: transform.translation.xy(),
+                angle: transform.rotation.to_euler(EulerRot::XYZ).2,
+                velocity: controller.velocity,
+            };
+            (
+                **player_client,
+                QuantizedPlayerState::from(&state),
+                state.position,
+            )
+        })
+        .collect();
+    let input_acks: HashMap<ClientId, u32> = players
+        .iter()
+        .filter_map(|(_, _, input_queue, player_client)| {
+            input_queue.map(|input_queue| (**player_client, input_queue.last_applied))
+        })
+        .collect();
+
+    for (client_id, encoder) in client_snapshots.iter_mut() {
+        let Some(viewer_position) = states
             .iter()
-            .map(|(transform, player_client)| {
-                (
-                    **player_client,
-                    RemotePlayerState {
-                        position: transform.translation.xy(),
-                        angle: transform.rotation.to_euler(EulerRot::XYZ).2,
-                    },
-                )
-            })
-            .collect(),
-    );
-    let bytes = match bincode::serialize(&msg) {
-        Ok(msg) => msg,
-        Err(err) => {
-            warn!("Failed to serlialize players message: {}", err);
-            return;
+            .find(|(other_id, _, _)| other_id == client_id)
+            .map(|(_, _, position)| *position)
+        else {
+            continue;
+        };
+        let interest = client_interests.entry(*client_id).or_default();
+
+        let mut updated = PlayerStates::default();
+        let mut deferred = HashSet::new();
+        for (other_id, state, position) in states.iter() {
+            // Clients always need their own player to reconcile against
+            let priority = if other_id == client_id {
+                1.0
+            } else {
+                interest_settings.priority(viewer_position.distance(*position))
+            };
+            if priority <= 0.0 {
+                continue;
+            }
+
+            if interest.accumulate(*other_id, priority) {
+                updated.insert(*other_id, *state);
+            } else {
+                deferred.insert(*other_id);
+            }
         }
-    };
-    server.broadcast_message(DefaultChannel::ReliableOrdered, bytes);
+        interest.retain(|other_id| updated.contains_key(other_id) || deferred.contains(other_id));
+
+        let input_ack = input_acks.get(client_id).copied().unwrap_or_default();
+        let msg = ServerMessage::Players(encoder.encode(*tick, input_ack, &updated, &deferred));
+        let bytes = match bincode::serialize(&msg) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Failed to serlialize players message: {}", err);
+                continue;
+            }
+        };
+        server.send_message(*client_id, ServerChannel::PlayerData, bytes);
+    }
 }
@@ -0,0 +1,456 @@
+use std::{collections::VecDeque, f32::consts::TAU};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use renet::ClientId;
+use serde::{Deserialize, Serialize};
+
+use crate::{remote_state::RemotePlayerState, tick::Tick};
+
+/// Size of the smallest representable step in positions, in world units.
+const POSITION_STEP: f32 = 1.0 / 64.0;
+
+/// Largest distance from the origin along either axis that survives
+/// quantization, in world units.
+pub const MAX_QUANTIZED_POSITION: f32 = i16::MAX as f32 * POSITION_STEP;
+
+/// Size of the smallest representable step in velocities, in units per second.
+const VELOCITY_STEP: f32 = 1.0 / 256.0;
+
+/// Largest speed along either axis that survives quantization, in units per
+/// second.
+pub const MAX_QUANTIZED_SPEED: f32 = i16::MAX as f32 * VELOCITY_STEP;
+
+/// Number of snapshots kept on both ends to encode and decode deltas against.
+/// If a client has not acknowledged any of them, it is sent full snapshots.
+const SNAPSHOT_HISTORY: usize = 64;
+
+/// Player state as sent over the network, rounded to fixed precision.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct QuantizedPlayerState {
+    x: i16,
+    y: i16,
+    angle: u16,
+    velocity_x: i16,
+    velocity_y: i16,
+}
+
+impl From<&RemotePlayerState> for QuantizedPlayerState {
+    fn from(state: &RemotePlayerState) -> Self {
+        Self {
+            x: (state.position.x / POSITION_STEP).round() as i16,
+            y: (state.position.y / POSITION_STEP).round() as i16,
+            angle: (state.angle.rem_euclid(TAU) / TAU * 65536.0).round() as u32 as u16,
+            velocity_x: (state.velocity.x / VELOCITY_STEP).round() as i16,
+            velocity_y: (state.velocity.y / VELOCITY_STEP).round() as i16,
+        }
+    }
+}
+
+impl QuantizedPlayerState {
+    pub fn dequantize(&self) -> RemotePlayerState {
+        RemotePlayerState {
+            position: Vec2::new(self.x as f32, self.y as f32) * POSITION_STEP,
+            angle: self.angle as f32 / 65536.0 * TAU,
+            velocity: Vec2::new(self.velocity_x as f32, self.velocity_y as f32) * VELOCITY_STEP,
+        }
+    }
+}
+
+/// Fields of a player's state that changed since the baseline.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct PlayerDelta {
+    x: Option<i16>,
+    y: Option<i16>,
+    angle: Option<u16>,
+    velocity_x: Option<i16>,
+    velocity_y: Option<i16>,
+}
+
+impl PlayerDelta {
+    fn between(baseline: Option<&QuantizedPlayerState>, state: &QuantizedPlayerState) -> Self {
+        fn changed<T: PartialEq + Copy>(baseline: Option<T>, value: T) -> Option<T> {
+            (baseline != Some(value)).then_some(value)
+        }
+
+        Self {
+            x: changed(baseline.map(|baseline| baseline.x), state.x),
+            y: changed(baseline.map(|baseline| baseline.y), state.y),
+            angle: changed(baseline.map(|baseline| baseline.angle), state.angle),
+            velocity_x: changed(
+                baseline.map(|baseline| baseline.velocity_x),
+                state.velocity_x,
+            ),
+            velocity_y: changed(
+                baseline.map(|baseline| baseline.velocity_y),
+                state.velocity_y,
+            ),
+        }
+    }
+
+    fn apply(&self, baseline: QuantizedPlayerState) -> QuantizedPlayerState {
+        QuantizedPlayerState {
+            x: self.x.unwrap_or(baseline.x),
+            y: self.y.unwrap_or(baseline.y),
+            angle: self.angle.unwrap_or(baseline.angle),
+            velocity_x: self.velocity_x.unwrap_or(baseline.velocity_x),
+            velocity_y: self.velocity_y.unwrap_or(baseline.velocity_y),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.x.is_none()
+            && self.y.is_none()
+            && self.angle.is_none()
+            && self.velocity_x.is_none()
+            && self.velocity_y.is_none()
+    }
+}
+
+/// Player states sent to a single client, encoded against a snapshot that the
+/// client has acknowledged.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub tick: Tick,
+
+    /// Tick of the snapshot this one is encoded against. Full snapshots have no
+    /// baseline.
+    pub baseline: Option<Tick>,
+
+    /// Sequence number of the last input the server applied for the receiving
+    /// client.
+    pub input_ack: u32,
+
+    /// Players whose state differs from the baseline. Players that are in the
+    /// baseline but not listed here are unchanged.
+    pub changed: Vec<(ClientId, PlayerDelta)>,
+
+    /// Players that were in the baseline but are no longer sent to this
+    /// client.
+    pub removed: Vec<ClientId>,
+
+    /// Players that are kept from the baseline without being updated on this
+    /// tick.
+    pub deferred: Vec<ClientId>,
+}
+
+pub type PlayerStates = HashMap<ClientId, QuantizedPlayerState>;
+
+/// Result of decoding a snapshot on the client.
+#[derive(Debug)]
+pub struct DecodedSnapshot {
+    /// States of the players updated on this tick.
+    pub players: PlayerStates,
+
+    /// Players the client should forget about.
+    pub removed: Vec<ClientId>,
+}
+
+/// Server-side record of the snapshots sent to a client.
+#[derive(Default)]
+pub struct SnapshotEncoder {
+    sent: VecDeque<(Tick, PlayerStates)>,
+    acked: Option<Tick>,
+}
+
+impl SnapshotEncoder {
+    /// Marks a snapshot as received by the client, making it the baseline for
+    /// following snapshots.
+    pub fn acknowledge(&mut self, tick: Tick) {
+        if self.acked.is_some_and(|acked| acked >= tick) {
+            return;
+        }
+        if !self.sent.iter().any(|(sent_tick, _)| *sent_tick == tick) {
+            return;
+        }
+
+        self.acked = Some(tick);
+        while self
+            .sent
+            .front()
+            .is_some_and(|(sent_tick, _)| *sent_tick < tick)
+        {
+            self.sent.pop_front();
+        }
+    }
+
+    /// Encodes the states of `players` against the acknowledged baseline.
+    /// Players in `deferred` keep the state the client already has for them,
+    /// and are left out until their next update if it has none.
+    pub fn encode(
+        &mut self,
+        tick: Tick,
+        input_ack: u32,
+        players: &PlayerStates,
+        deferred: &HashSet<ClientId>,
+    ) -> PlayerSnapshot {
+        let baseline = self
+            .acked
+            .and_then(|acked| self.sent.iter().find(|(sent_tick, _)| *sent_tick == acked));
+
+        // Deferred players stay in the snapshot so that they aren't removed
+        let mut players = players.clone();
+        let mut kept = Vec::new();
+        for client_id in deferred.iter() {
+            if players.contains_key(client_id) {
+                continue;
+            }
+            if let Some(state) = baseline.and_then(|(_, baseline)| baseline.get(client_id)) {
+                players.insert(*client_id, *state);
+                kept.push(*client_id);
+            }
+        }
+
+        let changed = players
+            .iter()
+            .filter(|(client_id, _)| !kept.contains(*client_id))
+            .filter_map(|(client_id, state)| {
+                let delta = PlayerDelta::between(
+                    baseline.and_then(|(_, baseline)| baseline.get(client_id)),
+                    state,
+                );
+                (!delta.is_empty()).then_some((*client_id, delta))
+            })
+            .collect();
+        let removed = baseline
+            .map(|(_, baseline)| {
+                baseline
+                    .keys()
+                    .filter(|client_id| !players.contains_key(*client_id))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let snapshot = PlayerSnapshot {
+            tick,
+            baseline: baseline.map(|(baseline_tick, _)| *baseline_tick),
+            input_ack,
+            changed,
+            removed,
+            deferred: kept,
+        };
+
+        // Keep the acknowledged baseline around even if the history is full
+        if self.sent.len() >= SNAPSHOT_HISTORY {
+            let index = if self.sent.front().map(|(sent_tick, _)| *sent_tick) == self.acked {
+                1
+            } else {
+                0
+            };
+            self.sent.remove(index);
+        }
+        self.sent.push_back((tick, players));
+
+        snapshot
+    }
+}
+
+/// Client-side reconstruction of snapshots from deltas.
+#[derive(Resource, Default)]
+pub struct SnapshotDecoder {
+    received: VecDeque<(Tick, PlayerStates)>,
+}
+
+impl SnapshotDecoder {
+    /// Tick of the newest decoded snapshot, which should be acknowledged to the
+    /// server.
+    pub fn latest_tick(&self) -> Option<Tick> {
+        self.received.back().map(|(tick, _)| *tick)
+    }
+
+    /// Reconstructs the player states of a snapshot. Returns `None` for
+    /// snapshots that arrive after a newer one, or whose baseline is unknown.
+    pub fn decode(&mut self, snapshot: PlayerSnapshot) -> Option<DecodedSnapshot> {
+        if self
+            .latest_tick()
+            .is_some_and(|latest| latest >= snapshot.tick)
+        {
+            return None;
+        }
+
+        let mut players = match snapshot.baseline {
+            Some(baseline_tick) => self
+                .received
+                .iter()
+                .find(|(tick, _)| *tick == baseline_tick)
+                .map(|(_, baseline)| baseline.clone())?,
+            None => PlayerStates::default(),
+        };
+
+        for client_id in snapshot.removed.iter() {
+            players.remove(client_id);
+        }
+        for (client_id, delta) in snapshot.changed.iter() {
+            let baseline = players.get(client_id).copied().unwrap_or_default();
+            players.insert(*client_id, delta.apply(baseline));
+        }
+
+        if self.received.len() >= SNAPSHOT_HISTORY {
+            self.received.pop_front();
+        }
+        self.received.push_back((snapshot.tick, players.clone()));
+
+        // Deferred players are only kept to decode later snapshots
+        for client_id in snapshot.deferred.iter() {
+            players.remove(client_id);
+        }
+
+        Some(DecodedSnapshot {
+            players,
+            removed: snapshot.removed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(x: f32, y: f32) -> QuantizedPlayerState {
+        QuantizedPlayerState::from(&RemotePlayerState {
+            position: Vec2::new(x, y),
+            angle: 1.0,
+            velocity: Vec2::new(2.0, -3.0),
+        })
+    }
+
+    fn states(players: &[(u64, QuantizedPlayerState)]) -> PlayerStates {
+        players
+            .iter()
+            .map(|(id, state)| (ClientId::from_raw(*id), *state))
+            .collect()
+    }
+
+    #[test]
+    fn quantization_keeps_state_within_a_step() {
+        let state = RemotePlayerState {
+            position: Vec2::new(12.345, -67.891),
+            angle: -2.5,
+            velocity: Vec2::new(4.321, -9.876),
+        };
+        let decoded = QuantizedPlayerState::from(&state).dequantize();
+
+        assert!((decoded.position - state.position).abs().max_element() <= POSITION_STEP / 2.0);
+        assert!((decoded.velocity - state.velocity).abs().max_element() <= VELOCITY_STEP / 2.0);
+        assert!((decoded.angle - state.angle.rem_euclid(TAU)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn first_snapshot_is_full() {
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+        let players = states(&[(1, player(1.0, 2.0)), (2, player(-3.0, 4.0))]);
+
+        let snapshot = encoder.encode(Tick(1), 0, &players, &HashSet::new());
+        assert_eq!(snapshot.baseline, None);
+        assert_eq!(snapshot.changed.len(), 2);
+
+        let decoded = decoder.decode(snapshot).unwrap();
+        assert_eq!(decoded.players, players);
+        assert!(decoded.removed.is_empty());
+    }
+
+    #[test]
+    fn delta_against_acknowledged_baseline_decodes_to_original() {
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+        let first = states(&[(1, player(1.0, 2.0)), (2, player(-3.0, 4.0))]);
+        decoder
+            .decode(encoder.encode(Tick(1), 0, &first, &HashSet::new()))
+            .unwrap();
+        encoder.acknowledge(Tick(1));
+
+        // Only the first player moves, and only along x
+        let second = states(&[(1, player(1.5, 2.0)), (2, player(-3.0, 4.0))]);
+        let snapshot = encoder.encode(Tick(2), 0, &second, &HashSet::new());
+        assert_eq!(snapshot.baseline, Some(Tick(1)));
+        assert_eq!(snapshot.changed.len(), 1);
+        let (client_id, delta) = &snapshot.changed[0];
+        assert_eq!(*client_id, ClientId::from_raw(1));
+        assert!(delta.x.is_some());
+        assert!(delta.y.is_none() && delta.angle.is_none());
+
+        let decoded = decoder.decode(snapshot).unwrap();
+        assert_eq!(decoded.players, second);
+    }
+
+    #[test]
+    fn unacknowledged_snapshots_are_sent_in_full() {
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+        let players = states(&[(1, player(1.0, 2.0))]);
+        encoder.encode(Tick(1), 0, &players, &HashSet::new());
+
+        // The first snapshot was lost, so the second can't build on it
+        let snapshot = encoder.encode(Tick(2), 0, &players, &HashSet::new());
+        assert_eq!(snapshot.baseline, None);
+        assert_eq!(snapshot.changed.len(), 1);
+        assert_eq!(decoder.decode(snapshot).unwrap().players, players);
+    }
+
+    #[test]
+    fn players_missing_from_the_baseline_are_removed() {
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+        let first = states(&[(1, player(1.0, 2.0)), (2, player(-3.0, 4.0))]);
+        decoder
+            .decode(encoder.encode(Tick(1), 0, &first, &HashSet::new()))
+            .unwrap();
+        encoder.acknowledge(Tick(1));
+
+        let second = states(&[(1, player(1.0, 2.0))]);
+        let snapshot = encoder.encode(Tick(2), 0, &second, &HashSet::new());
+        assert_eq!(snapshot.removed, vec![ClientId::from_raw(2)]);
+
+        let decoded = decoder.decode(snapshot).unwrap();
+        assert_eq!(decoded.players, second);
+        assert_eq!(decoded.removed, vec![ClientId::from_raw(2)]);
+    }
+
+    #[test]
+    fn deferred_players_are_kept_without_being_updated() {
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+        let first = states(&[(1, player(1.0, 2.0)), (2, player(-3.0, 4.0))]);
+        decoder
+            .decode(encoder.encode(Tick(1), 0, &first, &HashSet::new()))
+            .unwrap();
+        encoder.acknowledge(Tick(1));
+
+        let second = states(&[(1, player(1.5, 2.0))]);
+        let deferred = [ClientId::from_raw(2)].into_iter().collect();
+        let snapshot = encoder.encode(Tick(2), 0, &second, &deferred);
+        assert!(snapshot.removed.is_empty());
+
+        let decoded = decoder.decode(snapshot).unwrap();
+        assert_eq!(decoded.players, second);
+        assert!(decoded.removed.is_empty());
+    }
+
+    #[test]
+    fn snapshots_with_an_unknown_baseline_are_dropped() {
+        let mut encoder = SnapshotEncoder::default();
+        let players = states(&[(1, player(1.0, 2.0))]);
+        encoder.encode(Tick(1), 0, &players, &HashSet::new());
+        encoder.acknowledge(Tick(1));
+        let snapshot = encoder.encode(Tick(2), 0, &players, &HashSet::new());
+
+        // This decoder never received the baseline
+        let mut decoder = SnapshotDecoder::default();
+        assert!(decoder.decode(snapshot).is_none());
+    }
+
+    #[test]
+    fn old_snapshots_are_dropped() {
+        let mut encoder = SnapshotEncoder::default();
+        let mut decoder = SnapshotDecoder::default();
+        let players = states(&[(1, player(1.0, 2.0))]);
+        let old = encoder.encode(Tick(1), 0, &players, &HashSet::new());
+        let new = encoder.encode(Tick(2), 0, &players, &HashSet::new());
+
+        assert!(decoder.decode(new).is_some());
+        assert!(decoder.decode(old).is_none());
+    }
+}
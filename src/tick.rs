@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Number of fixed updates the simulation runs per second, unless the server is
+/// configured otherwise.
+pub const DEFAULT_TICK_RATE: f64 = 64.0;
+
+/// Number of fixed updates the simulation runs per second. Clients start out
+/// with the default and switch to the server's rate once they have joined.
+#[derive(Resource, Deref, Clone, Copy, Debug, PartialEq)]
+pub struct TickRate(pub f64);
+
+impl Default for TickRate {
+    fn default() -> Self {
+        Self(DEFAULT_TICK_RATE)
+    }
+}
+
+impl TickRate {
+    /// Number of ticks that pass in `duration`.
+    pub fn ticks(&self, duration: Duration) -> f64 {
+        duration.as_secs_f64() * self.0
+    }
+
+    /// Number of seconds that `ticks` ticks take.
+    pub fn seconds(&self, ticks: f64) -> f64 {
+        ticks / self.0
+    }
+}
+
+/// Counts fixed updates of the server simulation. Snapshots are stamped with the
+/// tick they were taken on so that clients can place them on a shared timeline.
+#[derive(
+    Resource,
+    Deref,
+    DerefMut,
+    Clone,
+    Copy,
+    Default,
+    Debug,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+pub struct Tick(pub u32);
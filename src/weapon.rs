@@ -0,0 +1,213 @@
+use std::{fmt, fs, io, ops::RangeInclusive, path::Path};
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    player_controller::{ActionButtons, PlayerController},
+    remote_state::WallImpact,
+};
+
+/// Most projectiles a single shot may fire.
+const MAX_PROJECTILE_COUNT: u32 = 32;
+
+/// Most weapons a server can offer, one for each number key.
+const MAX_WEAPONS: usize = 9;
+
+/// Range of shots per second a weapon may fire at.
+const FIRE_RATE_RANGE: RangeInclusive<f32> = 0.1..=100.0;
+
+/// Longest a reload may take, in seconds.
+const MAX_RELOAD_TIME: f32 = 30.0;
+
+#[derive(Debug)]
+pub enum WeaponsError {
+    Read(io::Error),
+    Parse(ron::error::SpannedError),
+    Invalid(String),
+}
+
+impl fmt::Display for WeaponsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Read(err) => write!(f, "failed to read weapons: {}", err),
+            Self::Parse(err) => write!(f, "invalid weapons: {}", err),
+            Self::Invalid(problem) => write!(f, "invalid weapons: {}", problem),
+        }
+    }
+}
+
+/// How a weapon behaves. Only the server knows these; clients learn everything
+/// they need to draw its projectiles from the replicated bullets.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeaponDefinition {
+    pub name: String,
+
+    /// Shots per second while the trigger is held.
+    pub fire_rate: f32,
+
+    /// Width of the cone that projectiles are spread over, in degrees.
+    pub spread: f32,
+
+    pub projectile_speed: f32,
+
+    /// Damage dealt by each projectile.
+    pub damage: f32,
+
+    pub magazine_size: u32,
+
+    /// Seconds it takes to reload.
+    pub reload_time: f32,
+
+    /// Number of projectiles fired per shot.
+    pub projectile_count: u32,
+
+    #[serde(default)]
+    pub wall_impact: WallImpact,
+}
+
+impl WeaponDefinition {
+    fn validate(&self) -> Result<(), String> {
+        let name = &self.name;
+
+        if !FIRE_RATE_RANGE.contains(&self.fire_rate) {
+            return Err(format!(
+                "{}: fire_rate must be from {} to {} shots per second",
+                name,
+                FIRE_RATE_RANGE.start(),
+                FIRE_RATE_RANGE.end()
+            ));
+        }
+        if !(self.spread.is_finite() && (0.0..360.0).contains(&self.spread)) {
+            return Err(format!("{}: spread must be from 0 to 360 degrees", name));
+        }
+        if !(self.projectile_speed.is_finite() && self.projectile_speed > 0.0) {
+            return Err(format!("{}: projectile_speed must be more than zero", name));
+        }
+        if !(self.damage.is_finite() && self.damage >= 0.0) {
+            return Err(format!("{}: damage must be zero or more", name));
+        }
+        if self.magazine_size == 0 {
+            return Err(format!("{}: magazine_size must be at least 1", name));
+        }
+        if !(0.0..=MAX_RELOAD_TIME).contains(&self.reload_time) {
+            return Err(format!(
+                "{}: reload_time must be from 0 to {} seconds",
+                name, MAX_RELOAD_TIME
+            ));
+        }
+        if !(1..=MAX_PROJECTILE_COUNT).contains(&self.projectile_count) {
+            return Err(format!(
+                "{}: projectile_count must be from 1 to {}",
+                name, MAX_PROJECTILE_COUNT
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Weapons players can choose from, selected by their index.
+#[derive(Resource, Clone, Debug, Deref, Deserialize)]
+#[serde(transparent)]
+pub struct Weapons(Vec<WeaponDefinition>);
+
+impl Default for Weapons {
+    /// The weapons in `weapons/default.ron`, used when the server is not given
+    /// any.
+    fn default() -> Self {
+        Self::parse(include_str!("../weapons/default.ron"))
+            .expect("built-in weapons should be valid")
+    }
+}
+
+impl Weapons {
+    /// Reads weapon definitions from a RON file and checks them.
+    pub fn load(path: &Path) -> Result<Self, WeaponsError> {
+        let text = fs::read_to_string(path).map_err(WeaponsError::Read)?;
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Self, WeaponsError> {
+        let weapons: Self = ron::from_str(text).map_err(WeaponsError::Parse)?;
+        weapons.validate().map_err(WeaponsError::Invalid)?;
+        Ok(weapons)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_WEAPONS).contains(&self.len()) {
+            return Err(format!("there must be 1 to {} weapons", MAX_WEAPONS));
+        }
+        self.iter().try_for_each(WeaponDefinition::validate)
+    }
+}
+
+/// Weapons carried by a player. Only exists on the server, which decides when
+/// players may fire so that clients can't shoot faster than their weapon.
+#[derive(Component)]
+pub struct Weapon {
+    /// Index of the weapon in hand.
+    selected: usize,
+
+    /// Rounds left in the magazine of every weapon.
+    ammo: Vec<u32>,
+
+    /// Earliest time the weapon in hand may fire again, in milliseconds since
+    /// epoch.
+    ready_at: u64,
+
+    /// When the reload in progress finishes, in milliseconds since epoch.
+    reloading_until: Option<u64>,
+}
+
+impl Weapon {
+    pub fn new(weapons: &Weapons) -> Self {
+        Self {
+            selected: 0,
+            ammo: weapons.iter().map(|weapon| weapon.magazine_size).collect(),
+            ready_at: 0,
+            reloading_until: None,
+        }
+    }
+
+    /// Switches and reloads weapons as the controller asks, and returns the
+    /// weapon in hand if it fires at time `now`, in milliseconds since epoch.
+    pub fn fire<'a>(
+        &mut self,
+        controller: &PlayerController,
+        weapons: &'a Weapons,
+        now: u64,
+    ) -> Option<&'a WeaponDefinition> {
+        // Switching weapons cancels reloading
+        let requested = controller.weapon as usize;
+        if requested != self.selected && requested < weapons.len() {
+            self.selected = requested;
+            self.reloading_until = None;
+        }
+        let weapon = weapons.get(self.selected)?;
+
+        if let Some(reloading_until) = self.reloading_until {
+            if now < reloading_until {
+                return None;
+            }
+            self.ammo[self.selected] = weapon.magazine_size;
+            self.reloading_until = None;
+        }
+
+        // Empty weapons reload instead of firing
+        let ammo = self.ammo[self.selected];
+        let firing = controller.buttons.contains(ActionButtons::FIRE);
+        let reloading = controller.buttons.contains(ActionButtons::RELOAD);
+        if (reloading && ammo < weapon.magazine_size) || (firing && ammo == 0) {
+            self.reloading_until = Some(now.saturating_add((weapon.reload_time * 1000.0) as u64));
+            return None;
+        }
+
+        if !firing || now < self.ready_at {
+            return None;
+        }
+        self.ammo[self.selected] -= 1;
+        self.ready_at = now.saturating_add((1000.0 / weapon.fire_rate) as u64);
+        Some(weapon)
+    }
+}